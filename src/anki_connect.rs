@@ -1,112 +1,448 @@
-use anyhow::{anyhow, bail, Result};
-use futures::future::try_join_all;
-use log::info;
-use maud::html;
+use std::io::IsTerminal;
+use std::time;
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use inquire::Confirm;
+use itertools::Itertools;
+use log::{info, warn};
 use reqwest::header::{self, HeaderValue};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use serde_json::{json, Value};
 
 use crate::model::{Book, Word};
+use crate::render::CardRenderer;
+use crate::settings::{AnkiSettings, Service};
+use crate::util;
+
+const AUDIO_FILE_PREFIX: &str = "anki_words_";
 
 pub struct AnkiConnectClient {
     http: reqwest::Client,
+    settings: AnkiSettings,
+    renderer: CardRenderer,
+}
+
+#[derive(Debug, Default)]
+pub struct StoreReport {
+    pub added: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+    pub found_in_other_decks: Vec<(String, Vec<String>)>,
+}
+
+impl StoreReport {
+    pub fn merge(&mut self, other: StoreReport) {
+        self.added += other.added;
+        self.duplicates += other.duplicates;
+        self.failed += other.failed;
+        self.found_in_other_decks.extend(other.found_in_other_decks);
+    }
 }
 
-const ENDPOINT_URL: &str = "http://localhost:8765";
+#[derive(Deserialize)]
+struct AnkiResponse {
+    #[serde(default)]
+    result: Value,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CardInfo {
+    #[serde(rename = "deckName")]
+    deck_name: String,
+}
 
 impl AnkiConnectClient {
-    pub fn new() -> Result<AnkiConnectClient> {
+    pub fn new(settings: AnkiSettings, renderer: CardRenderer) -> Result<AnkiConnectClient> {
         let mut default_headers = header::HeaderMap::new();
         default_headers.insert("Accept", HeaderValue::from_str("application/json")?);
         default_headers.insert("Content-Type", HeaderValue::from_str("application/json")?);
 
-        let http = reqwest::Client::builder()
-            .default_headers(default_headers)
-            .connection_verbose(true)
-            .build()?;
+        let http = util::http_client(default_headers)?;
+
+        Ok(AnkiConnectClient { http, settings, renderer })
+    }
+
+    pub async fn version(&self) -> Result<u32> {
+        let request = json!({
+            "version": 6,
+            "action": "version"
+        });
+
+        self.make_request_for_result(request).await
+    }
+
+    pub async fn validate_note_type(&self) -> Result<()> {
+        let note = &self.settings.note;
+
+        let request = json!({
+            "version": 6,
+            "action": "modelNames"
+        });
+        let model_names: Vec<String> = self.make_request_for_result(request).await?;
+
+        if !model_names.contains(&note.model_name) {
+            bail!("Anki note type '{}' doesn't exist, available note types: {}",
+                note.model_name, model_names.join(", "));
+        }
+
+        let request = json!({
+            "version": 6,
+            "action": "modelFieldNames",
+            "params": {
+                "modelName": note.model_name
+            }
+        });
+        let field_names: Vec<String> = self.make_request_for_result(request).await?;
+
+        for field in [&note.front_field, &note.back_field] {
+            if !field_names.contains(field) {
+                bail!("Anki note type '{}' has no field '{field}', available fields: {}",
+                    note.model_name, field_names.join(", "));
+            }
+        }
 
-        Ok(AnkiConnectClient { http })
+        Ok(())
     }
 
-    pub async fn store_book(&self, book: &Book, words: &Vec<Word>, force: bool) -> Result<()> {
+    pub async fn store_book(&self, book: &Book, words: &Vec<Word>, force: bool) -> Result<StoreReport> {
+        let deck_name = self.settings.deck_name(book);
+
         if force {
-            self.delete_deck(&book.title).await?;
+            self.delete_deck(&deck_name).await?;
         }
 
-        self.create_deck_if_not_exists(&book.title).await?;
+        self.create_deck_if_not_exists(&deck_name).await?;
+
+        let mut report = StoreReport::default();
+        let mut words_to_add = Vec::new();
 
         for word in words {
-            self.add_word(&book.title, word).await?
+            if self.settings.dedupe_across_decks || self.settings.global_dedupe {
+                let other_decks = self.find_other_decks(&deck_name, &word.text).await?;
+
+                if !other_decks.is_empty() {
+                    info!("'{word}' already exists in other decks: {}", other_decks.join(", "));
+                    report.found_in_other_decks.push((word.text.to_owned(), other_decks));
+
+                    if self.settings.global_dedupe {
+                        continue;
+                    }
+                }
+            }
+
+            words_to_add.push(word);
         }
 
-        Ok(())
+        self.add_words(Some(book), &deck_name, &words_to_add, &mut report).await?;
+        self.sync_if_enabled().await;
+
+        Ok(report)
     }
 
-    async fn add_word(&self, deck_name: &str, word: &Word) -> Result<()> {
-        let html = Self::generate_back_text_html(word)?;
+    pub async fn add_word(&self, book: Option<&Book>, deck_name: &str, word: &Word) -> Result<StoreReport> {
+        self.create_deck_if_not_exists(deck_name).await?;
 
-        self.add_note(deck_name, &word.text, &html).await?;
+        let mut report = StoreReport::default();
+        self.add_words(book, deck_name, &[word], &mut report).await?;
+        self.sync_if_enabled().await;
 
-        Ok(())
+        Ok(report)
     }
 
-    fn generate_back_text_html(word: &Word) -> Result<String> {
-        let back_text = html! {
-            p { (word.translation.as_ref().unwrap()) }
+    pub async fn update_word(&self, book: &Book, previous: &Word, word: &Word) -> Result<()> {
+        let deck_name = self.settings.deck_name(book);
+        let direction = self.settings.card_direction;
+        let mut updated = 0;
 
-            ol type="I" {
-                @for (category, definitions) in word.definitions.as_ref().unwrap() {
-                    li {
-                        p { (category) }
+        if direction.has_forward() {
+            let back_text = self.renderer.back_html(word)?;
+            updated += self.update_notes(&deck_name, &previous.text, false, &word.text, &back_text).await?;
+        }
 
-                        ol type="1" {
-                            @for definition in definitions {
-                                li {
-                                    p { (definition.definition.as_ref().unwrap()) }
+        if direction.has_reverse() {
+            let translation = |word: &Word| word.translation.clone().filter(|translation| !translation.is_empty());
 
-                                    ul {
-                                        @for example in &definition.examples {
-                                            li { (example) }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+            // Reverse notes are found by the translation they were created with
+            match (translation(previous), translation(word)) {
+                (Some(previous_translation), Some(translation)) => {
+                    let back_text = self.renderer.reverse_back_html(word)?;
+                    updated += self.update_notes(&deck_name, &previous_translation, true, &translation, &back_text).await?;
                 }
+                _ => info!("'{word}' has no translation, leaving its reverse card as is"),
             }
-        }.into_string();
+        }
+
+        if updated == 0 {
+            bail!("No note for '{}' found in deck '{deck_name}'", previous.text);
+        }
 
-        Ok(back_text)
+        Ok(())
     }
 
-    async fn add_note(&self, deck_name: &str, front_text: &str, back_text: &str) -> Result<()> {
+    async fn update_notes(&self, deck_name: &str, front_text: &str, reverse: bool, new_front_text: &str,
+                          back_text: &str) -> Result<usize> {
+        let reverse_tag = if reverse { "tag:reverse" } else { "-tag:reverse" };
         let request = json!({
             "version": 6,
-            "action": "addNote",
+            "action": "findNotes",
             "params": {
-                "note": {
-                    "deckName": deck_name,
-                    "modelName": "Basic",
-                    "fields": {
-                        "Front": front_text,
-                        "Back": back_text
-                    },
-                    "options": {
-                        "allowDuplicate": false,
-                        "duplicateScope": "deck",
-                        "duplicateScopeOptions": {
-                            "deckName": deck_name
+                "query": format!("{} \"{}:{}\" {reverse_tag}",
+                    deck_query(deck_name), escape_query(&self.settings.note.front_field), escape_query(front_text))
+            }
+        });
+        let note_ids: Vec<u64> = self.make_request_for_result(request).await?;
+        let count = note_ids.len();
+
+        for note_id in note_ids {
+            let request = json!({
+                "version": 6,
+                "action": "updateNoteFields",
+                "params": {
+                    "note": {
+                        "id": note_id,
+                        "fields": {
+                            &self.settings.note.front_field: new_front_text,
+                            &self.settings.note.back_field: back_text
                         }
                     }
                 }
+            });
+
+            self.make_request(request).await?;
+        }
+
+        Ok(count)
+    }
+
+    async fn sync_if_enabled(&self) {
+        if self.settings.sync {
+            if let Err(err) = self.sync().await {
+                warn!("Anki sync failed, the cards are only stored locally: {err}");
+            }
+        }
+    }
+
+    async fn sync(&self) -> Result<()> {
+        let request = json!({
+            "version": 6,
+            "action": "sync"
+        });
+        self.make_request(request).await?;
+
+        info!("Synced Anki collection");
+
+        Ok(())
+    }
+
+    pub async fn store_audio(&self, word_stem: &str, url: &str, data: &[u8]) -> Result<String> {
+        let extension = url.rsplit_once('.')
+            .map(|(_, extension)| extension)
+            .filter(|extension| extension.chars().all(char::is_alphanumeric))
+            .unwrap_or("mp3");
+        let stem: String = word_stem.chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        let filename = format!("{AUDIO_FILE_PREFIX}{stem}.{extension}");
+
+        let request = json!({
+            "version": 6,
+            "action": "storeMediaFile",
+            "params": {
+                "filename": filename,
+                "data": STANDARD.encode(data)
             }
         });
 
         self.make_request(request).await?;
 
+        Ok(filename)
+    }
+
+    pub async fn audio_file_names(&self) -> Result<Vec<String>> {
+        let request = json!({
+            "version": 6,
+            "action": "getMediaFilesNames",
+            "params": {
+                "pattern": format!("{AUDIO_FILE_PREFIX}*")
+            }
+        });
+
+        self.make_request_for_result(request).await
+    }
+
+    async fn find_other_decks(&self, deck_name: &str, front_text: &str) -> Result<Vec<String>> {
+        let request = json!({
+            "version": 6,
+            "action": "findCards",
+            "params": {
+                "query": format!("\"{}:{}\" -\"deck:{}\"",
+                    escape_query(&self.settings.note.front_field), escape_query(front_text), escape_query(deck_name))
+            }
+        });
+        let card_ids: Vec<u64> = self.make_request_for_result(request).await?;
+
+        if card_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let request = json!({
+            "version": 6,
+            "action": "cardsInfo",
+            "params": {
+                "cards": card_ids
+            }
+        });
+        let cards: Vec<CardInfo> = self.make_request_for_result(request).await?;
+
+        Ok(cards.into_iter()
+            .map(|card| card.deck_name)
+            .unique()
+            .collect())
+    }
+
+    async fn add_words(&self, book: Option<&Book>, deck_name: &str, words: &[&Word], report: &mut StoreReport) -> Result<()> {
+        if words.is_empty() {
+            return Ok(());
+        }
+
+        let mut word_notes = Vec::new();
+        for word in words {
+            for note in self.notes(book, deck_name, word)? {
+                word_notes.push((*word, note));
+            }
+        }
+        let (words, notes): (Vec<&Word>, Vec<Value>) = word_notes.into_iter().unzip();
+
+        let total = notes.len();
+        let addable = self.can_add_notes(&notes).await?;
+        let (words, notes): (Vec<&Word>, Vec<Value>) = words.iter().zip(notes).zip(addable)
+            .filter(|(_, addable)| *addable)
+            .map(|(word_and_note, _)| word_and_note)
+            .unzip();
+
+        let duplicates = total - words.len();
+        report.duplicates += duplicates;
+        info!("{} new notes, {duplicates} already present in deck '{deck_name}'", words.len());
+
+        if words.is_empty() {
+            return Ok(());
+        }
+
+        let note_ids = self.add_notes(notes).await?;
+
+        let failed_words = words.iter().zip(&note_ids)
+            .filter(|(_, note_id)| note_id.is_none())
+            .map(|(word, _)| word.text.as_str())
+            .collect_vec();
+
+        if !failed_words.is_empty() {
+            warn!("Failed to add {} notes to deck '{deck_name}': {}", failed_words.len(), failed_words.join(", "));
+        }
+
+        report.failed += failed_words.len();
+        report.added += note_ids.len() - failed_words.len();
+
         Ok(())
     }
 
+    async fn can_add_notes(&self, notes: &[Value]) -> Result<Vec<bool>> {
+        let request = json!({
+            "version": 6,
+            "action": "canAddNotes",
+            "params": {
+                "notes": notes
+            }
+        });
+
+        let addable: Vec<bool> = self.make_request_for_result(request).await?;
+        if addable.len() != notes.len() {
+            bail!("Anki returned {} results for {} notes", addable.len(), notes.len());
+        }
+
+        Ok(addable)
+    }
+
+    async fn add_notes(&self, notes: Vec<Value>) -> Result<Vec<Option<u64>>> {
+        let count = notes.len();
+        let request = json!({
+            "version": 6,
+            "action": "addNotes",
+            "params": {
+                "notes": notes
+            }
+        });
+
+        let note_ids: Vec<Option<u64>> = self.make_request_for_result(request).await?;
+        if note_ids.len() != count {
+            bail!("Anki returned {} results for {count} notes", note_ids.len());
+        }
+
+        Ok(note_ids)
+    }
+
+    fn notes(&self, book: Option<&Book>, deck_name: &str, word: &Word) -> Result<Vec<Value>> {
+        let direction = self.settings.card_direction;
+        let tags = self.tags(book, word);
+        let mut notes = Vec::new();
+
+        if direction.has_forward() {
+            notes.push(self.note(deck_name, &word.text, &self.renderer.back_html(word)?, tags.clone()));
+        }
+
+        if direction.has_reverse() {
+            match word.translation.as_deref().filter(|translation| !translation.is_empty()) {
+                Some(translation) => {
+                    let tags = tags.into_iter().chain(["reverse".to_string()]).collect();
+                    notes.push(self.note(deck_name, translation, &self.renderer.reverse_back_html(word)?, tags));
+                }
+                None => info!("'{word}' has no translation, skipping its reverse card"),
+            }
+        }
+
+        Ok(notes)
+    }
+
+    fn note(&self, deck_name: &str, front_text: &str, back_text: &str, tags: Vec<String>) -> Value {
+        json!({
+            "deckName": deck_name,
+            "modelName": self.settings.note.model_name,
+            "fields": {
+                &self.settings.note.front_field: front_text,
+                &self.settings.note.back_field: back_text
+            },
+            "tags": tags,
+            "options": {
+                "allowDuplicate": false,
+                "duplicateScope": "deck",
+                "duplicateScopeOptions": {
+                    "deckName": deck_name
+                }
+            }
+        })
+    }
+
+    fn tags(&self, book: Option<&Book>, word: &Word) -> Vec<String> {
+        let settings = &self.settings.tags;
+
+        let book_tag = book.map(|book| book.title.to_lowercase()).filter(|_| settings.book);
+        let category_tags = word.definitions.iter()
+            .filter(|_| settings.categories)
+            .flat_map(|definitions| definitions.keys().sorted())
+            .map(|category| category.to_string());
+
+        settings.constant.iter().cloned()
+            .chain(book_tag)
+            .chain(category_tags)
+            .map(|tag| sanitize_tag(&tag))
+            .filter(|tag| !tag.is_empty())
+            .unique()
+            .collect()
+    }
+
     async fn create_deck_if_not_exists(&self, deck_name: &str) -> Result<()> {
         let existing_decks = self.get_decks().await?;
 
@@ -122,24 +458,7 @@ impl AnkiConnectClient {
             "version": 6,
             "action": "deckNames"
         });
-        let text = self.make_request(request).await?;
-
-        let response: Value = serde_json::from_str(&text).unwrap();
-        let results = response.as_object().ok_or(anyhow!("Failed to map response to object"))?
-            .get("result").ok_or(anyhow!("Failed to get 'result' field"))?
-            .as_array().ok_or(anyhow!("Failed to map 'result' to array"))?
-            .iter();
-
-        let mut decks = Vec::new();
-        for value in results {
-            decks.push(
-                value.as_str()
-                    .ok_or(anyhow!("Failed to map 'result' array value to string"))?
-                    .to_string()
-            );
-        }
-
-        Ok(decks)
+        self.make_request_for_result(request).await
     }
 
     async fn create_deck(&self, deck_name: &str) -> Result<()> {
@@ -156,7 +475,42 @@ impl AnkiConnectClient {
         Ok(())
     }
 
-    async fn delete_deck(&self, deck_name: &str) -> Result<()> {
+    pub async fn count_cards(&self, deck_name: &str) -> Result<usize> {
+        let request = json!({
+            "version": 6,
+            "action": "findCards",
+            "params": {
+                "query": format!("\"deck:{}\"", escape_query(deck_name))
+            }
+        });
+        let card_ids: Vec<u64> = self.make_request_for_result(request).await?;
+
+        Ok(card_ids.len())
+    }
+
+    // Deleting a deck also deletes its cards, including any edits made in Anki
+    pub async fn confirm_delete_deck(&self, deck_name: &str, assume_yes: bool) -> Result<()> {
+        let card_count = self.count_cards(deck_name).await?;
+        if card_count == 0 || assume_yes {
+            return Ok(());
+        }
+
+        if !std::io::stdin().is_terminal() {
+            bail!("Refusing to delete deck '{deck_name}' with {card_count} cards without confirmation, pass --yes to delete it");
+        }
+
+        let confirmed = Confirm::new(&format!("Delete deck '{deck_name}' with {card_count} cards?"))
+            .with_default(false)
+            .prompt()?;
+
+        if !confirmed {
+            bail!("Not deleting deck '{deck_name}'");
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete_deck(&self, deck_name: &str) -> Result<()> {
         let request = json!({
             "version": 6,
             "action": "deleteDecks",
@@ -171,15 +525,435 @@ impl AnkiConnectClient {
         Ok(())
     }
 
-    async fn make_request(&self, request: Value) -> Result<String> {
-        let response = self.http.post(ENDPOINT_URL)
-            .body(request.to_string())
-            .send().await?;
+    async fn make_request_for_result<T: DeserializeOwned>(&self, request: Value) -> Result<T> {
+        let action = request["action"].as_str().unwrap_or_default().to_owned();
+        let result = self.make_request(request).await?;
+
+        serde_json::from_value(result)
+            .with_context(|| format!("Failed to map the result of Anki action '{action}'"))
+    }
+
+    // AnkiConnect reports failed actions with a 200 status and a non-null `error` in the body
+    async fn make_request(&self, request: Value) -> Result<Value> {
+        let action = request["action"].as_str().unwrap_or_default().to_owned();
+        let text = self.post(request).await?;
+
+        let response: AnkiResponse = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse the response to Anki action '{action}'"))?;
+
+        match response.error {
+            Some(error) => bail!("Anki action '{action}' failed: {error}"),
+            None => Ok(response.result),
+        }
+    }
+
+    async fn post(&self, request: Value) -> Result<String> {
+        let endpoint = &self.settings.endpoint;
+        let max_retries = self.settings.max_retries;
+        let mut last_failure = String::new();
+
+        for attempt in 0..=max_retries {
+            util::count_request(Service::Anki);
+            match self.http.post(endpoint).body(request.to_string()).send().await {
+                Ok(response) if response.status().is_success() => return Ok(response.text().await?),
+                Ok(response) => last_failure = format!("status {}", response.status()),
+                Err(err) if err.is_connect() && attempt == max_retries => {
+                    bail!("Couldn't connect to AnkiConnect at '{endpoint}', is Anki open with AnkiConnect installed?");
+                }
+                Err(err) => last_failure = util::describe_request_error(&err),
+            }
+
+            if attempt < max_retries {
+                let delay = self.backoff(attempt);
+                info!("Request to Anki failed with {last_failure}, waiting {} ms...", delay.as_millis());
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        bail!("Request to Anki failed after {} attempts, last failure: {last_failure}", max_retries + 1);
+    }
+
+    // Jitter keeps concurrent retries from hitting a busy Anki at the same moment
+    fn backoff(&self, attempt: u32) -> time::Duration {
+        let base = self.settings.backoff_base_ms.saturating_mul(2u64.saturating_pow(attempt));
+        let jitter = fastrand::u64(0..=base / 2);
+
+        time::Duration::from_millis(base + jitter)
+    }
+}
+
+fn sanitize_tag(tag: &str) -> String {
+    tag.split_whitespace().join("_")
+}
+
+// Anki search treats `*` and `_` as wildcards, and `\` escapes them
+fn escape_query(text: &str) -> String {
+    text.chars()
+        .fold(String::with_capacity(text.len()), |mut escaped, char| {
+            if matches!(char, '\\' | '"' | '*' | '_') {
+                escaped.push('\\');
+            }
+            escaped.push(char);
+            escaped
+        })
+}
+
+// `deck:` also matches the subdecks
+fn deck_query(deck_name: &str) -> String {
+    let deck_name = escape_query(deck_name);
+
+    format!("\"deck:{deck_name}\" -\"deck:{deck_name}::*\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::model::{BookSource, Definition, DefinitionCategory};
+    use crate::settings::{CardDirection, RenderingSettings};
+
+    type Respond = fn(&Value) -> Value;
+
+    // Stands in for AnkiConnect: records every request and answers it with `respond`
+    struct MockAnki {
+        endpoint: String,
+        requests: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl MockAnki {
+        async fn start(respond: Respond) -> MockAnki {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+
+            let recorded = requests.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(Self::serve(stream, respond, recorded.clone()));
+                }
+            });
+
+            MockAnki { endpoint, requests }
+        }
+
+        async fn serve(mut stream: TcpStream, respond: Respond, recorded: Arc<Mutex<Vec<Value>>>) {
+            let mut buf = Vec::new();
+            let mut chunk = [0; 4096];
+
+            let header_end = loop {
+                let read = stream.read(&mut chunk).await.unwrap();
+                if read == 0 {
+                    return;
+                }
+                buf.extend_from_slice(&chunk[..read]);
+
+                if let Some(position) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break position + 4;
+                }
+            };
+
+            let headers = String::from_utf8_lossy(&buf[..header_end]).to_lowercase();
+            let content_length: usize = headers.lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map(|value| value.trim().parse().unwrap())
+                .unwrap_or(0);
+
+            while buf.len() < header_end + content_length {
+                let read = stream.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..read]);
+            }
+
+            let request: Value = serde_json::from_slice(&buf[header_end..header_end + content_length]).unwrap();
+            let body = respond(&request).to_string();
+            recorded.lock().unwrap().push(request);
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+
+        fn client(&self) -> AnkiConnectClient {
+            self.client_with_direction(CardDirection::default())
+        }
+
+        fn client_with_direction(&self, card_direction: CardDirection) -> AnkiConnectClient {
+            let settings = AnkiSettings {
+                endpoint: self.endpoint.clone(),
+                max_retries: 0,
+                card_direction,
+                ..AnkiSettings::default()
+            };
+            let renderer = CardRenderer::new(RenderingSettings::default()).unwrap();
+
+            AnkiConnectClient::new(settings, renderer).unwrap()
+        }
+
+        fn requests(&self) -> Vec<Value> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn actions(&self) -> Vec<String> {
+            self.requests().iter()
+                .map(|request| request["action"].as_str().unwrap().to_owned())
+                .collect()
+        }
+
+        fn request(&self, action: &str) -> Value {
+            self.requests().into_iter()
+                .find(|request| request["action"] == action)
+                .unwrap_or_else(|| panic!("No '{action}' request was sent"))
+        }
+    }
+
+    fn ok(result: Value) -> Value {
+        json!({ "result": result, "error": null })
+    }
+
+    // Anki with only the default deck, accepting every note
+    fn anki(request: &Value) -> Value {
+        let note_count = request["params"]["notes"].as_array().map(Vec::len).unwrap_or(0);
+
+        match request["action"].as_str().unwrap() {
+            "deckNames" => ok(json!(["Default"])),
+            "createDeck" => ok(json!(1)),
+            "canAddNotes" => ok(json!(vec![true; note_count])),
+            "addNotes" => ok(json!((1..=note_count as u64).collect_vec())),
+            "getMediaFilesNames" => ok(json!(["anki_words_run.mp3"])),
+            "findNotes" => ok(json!([1])),
+            "updateNoteFields" => ok(json!(null)),
+            action => json!({ "result": null, "error": format!("unsupported action {action}") }),
+        }
+    }
+
+    fn book() -> Book {
+        Book { id: 1, title: "Test Book".to_string(), author: None, source: BookSource::Readwise }
+    }
+
+    fn word(text: &str, translation: &str) -> Word {
+        let definition = Definition {
+            definition: Some(format!("definition of {text}")),
+            examples: vec![format!("example of {text}")],
+            grammatical_features: Vec::new(),
+            registers: Vec::new(),
+        };
+
+        Word {
+            translation: Some(translation.to_string()),
+            definitions: Some(HashMap::from([(DefinitionCategory::Verb, vec![definition])])),
+            ..Word::from_text(text)
+        }
+    }
+
+    #[tokio::test]
+    async fn deck_names_request() {
+        let anki = MockAnki::start(anki).await;
+
+        let decks = anki.client().get_decks().await.unwrap();
+
+        assert_eq!(decks, vec!["Default"]);
+        assert_eq!(anki.requests(), vec![json!({ "version": 6, "action": "deckNames" })]);
+    }
+
+    #[tokio::test]
+    async fn creates_missing_deck() {
+        let anki = MockAnki::start(anki).await;
+
+        anki.client().create_deck_if_not_exists("Test Book").await.unwrap();
+
+        assert_eq!(anki.actions(), vec!["deckNames", "createDeck"]);
+        assert_eq!(anki.request("createDeck"), json!({
+            "version": 6,
+            "action": "createDeck",
+            "params": { "deck": "Test Book" }
+        }));
+    }
+
+    #[tokio::test]
+    async fn keeps_existing_deck() {
+        let anki = MockAnki::start(anki).await;
+
+        anki.client().create_deck_if_not_exists("Default").await.unwrap();
+
+        assert_eq!(anki.actions(), vec!["deckNames"]);
+    }
+
+    #[tokio::test]
+    async fn add_word_sends_note() {
+        let anki = MockAnki::start(anki).await;
+        let client = anki.client();
+        let word = word("run", "бежать");
+
+        let report = client.add_word(Some(&book()), "Test Book", &word).await.unwrap();
+
+        assert_eq!(report.added, 1);
+        assert_eq!(anki.request("addNotes"), json!({
+            "version": 6,
+            "action": "addNotes",
+            "params": {
+                "notes": [{
+                    "deckName": "Test Book",
+                    "modelName": "Basic",
+                    "fields": {
+                        "Front": "run",
+                        "Back": client.renderer.back_html(&word).unwrap()
+                    },
+                    "tags": ["anki-words-importer", "test_book", "verb"],
+                    "options": {
+                        "allowDuplicate": false,
+                        "duplicateScope": "deck",
+                        "duplicateScopeOptions": { "deckName": "Test Book" }
+                    }
+                }]
+            }
+        }));
+    }
+
+    #[tokio::test]
+    async fn store_book_adds_every_new_word() {
+        let anki = MockAnki::start(anki).await;
+        let words = vec![word("run", "бежать"), word("give", "давать")];
+
+        let report = anki.client().store_book(&book(), &words, false).await.unwrap();
+
+        assert_eq!(anki.actions(), vec!["deckNames", "createDeck", "canAddNotes", "addNotes"]);
+        assert_eq!(anki.request("canAddNotes")["params"]["notes"], anki.request("addNotes")["params"]["notes"]);
+        assert_eq!((report.added, report.duplicates, report.failed), (2, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn store_book_skips_duplicates() {
+        fn first_is_duplicate(request: &Value) -> Value {
+            match request["action"].as_str().unwrap() {
+                "canAddNotes" => ok(json!([false, true])),
+                _ => anki(request),
+            }
+        }
+        let anki = MockAnki::start(first_is_duplicate).await;
+        let words = vec![word("run", "бежать"), word("give", "давать")];
+
+        let report = anki.client().store_book(&book(), &words, false).await.unwrap();
 
-        if !response.status().is_success() {
-            bail!("Request to Anki failed");
+        let notes = anki.request("addNotes")["params"]["notes"].clone();
+        assert_eq!(notes.as_array().unwrap().len(), 1);
+        assert_eq!(notes[0]["fields"]["Front"], "give");
+        assert_eq!((report.added, report.duplicates, report.failed), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn store_book_counts_rejected_notes() {
+        fn first_is_rejected(request: &Value) -> Value {
+            match request["action"].as_str().unwrap() {
+                "addNotes" => ok(json!([null, 2])),
+                _ => anki(request),
+            }
         }
+        let anki = MockAnki::start(first_is_rejected).await;
+        let words = vec![word("run", "бежать"), word("give", "давать")];
+
+        let report = anki.client().store_book(&book(), &words, false).await.unwrap();
+
+        assert_eq!((report.added, report.duplicates, report.failed), (1, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn lists_audio_files() {
+        let anki = MockAnki::start(anki).await;
+
+        let audio_files = anki.client().audio_file_names().await.unwrap();
+
+        assert_eq!(audio_files, vec!["anki_words_run.mp3"]);
+        assert_eq!(anki.requests(), vec![json!({
+            "version": 6,
+            "action": "getMediaFilesNames",
+            "params": { "pattern": "anki_words_*" }
+        })]);
+    }
+
+    #[tokio::test]
+    async fn reports_action_errors() {
+        fn deck_error(request: &Value) -> Value {
+            match request["action"].as_str().unwrap() {
+                "createDeck" => json!({ "result": null, "error": "collection is not available" }),
+                _ => anki(request),
+            }
+        }
+        let anki = MockAnki::start(deck_error).await;
+
+        let err = anki.client().store_book(&book(), &vec![word("run", "бежать")], false).await.unwrap_err();
+
+        assert_eq!(err.to_string(), "Anki action 'createDeck' failed: collection is not available");
+        assert!(!anki.actions().contains(&"addNotes".to_string()));
+    }
+
+    #[tokio::test]
+    async fn update_word_updates_forward_and_reverse_notes() {
+        let anki = MockAnki::start(anki).await;
+        let client = anki.client_with_direction(CardDirection::Both);
+        let word = word("run", "бегать");
+
+        client.update_word(&book(), &self::word("ran", "бежал"), &word).await.unwrap();
+
+        let queries = anki.requests().into_iter()
+            .filter(|request| request["action"] == "findNotes")
+            .map(|request| request["params"]["query"].as_str().unwrap().to_owned())
+            .collect_vec();
+        assert_eq!(queries, vec![
+            r#""deck:Test Book" -"deck:Test Book::*" "Front:ran" -tag:reverse"#,
+            r#""deck:Test Book" -"deck:Test Book::*" "Front:бежал" tag:reverse"#,
+        ]);
+
+        let fronts = anki.requests().into_iter()
+            .filter(|request| request["action"] == "updateNoteFields")
+            .map(|request| request["params"]["note"]["fields"]["Front"].clone())
+            .collect_vec();
+        assert_eq!(fronts, vec![json!("run"), json!("бегать")]);
+    }
+
+    #[tokio::test]
+    async fn update_word_escapes_wildcards() {
+        let anki = MockAnki::start(anki).await;
+        let book = Book { title: "Self_made".to_string(), ..book() };
+
+        anki.client().update_word(&book, &word("self_made", "сам"), &word("self_made", "сам")).await.unwrap();
+
+        assert_eq!(anki.request("findNotes")["params"]["query"],
+            r#""deck:Self\_made" -"deck:Self\_made::*" "Front:self\_made" -tag:reverse"#);
+    }
+
+    #[tokio::test]
+    async fn update_word_sends_escaped_queries() {
+        let anki = MockAnki::start(anki).await;
+        let client = anki.client_with_direction(CardDirection::Both);
+        let book = Book { title: "Notes*".to_string(), ..book() };
+
+        client.update_word(&book, &word(r#"say "hi"\*"#, "привет_*"), &word("hi", "привет")).await.unwrap();
+
+        let queries = anki.requests().into_iter()
+            .filter(|request| request["action"] == "findNotes")
+            .map(|request| request["params"]["query"].as_str().unwrap().to_owned())
+            .collect_vec();
+        assert_eq!(queries, vec![
+            r#""deck:Notes\*" -"deck:Notes\*::*" "Front:say \"hi\"\\\*" -tag:reverse"#,
+            r#""deck:Notes\*" -"deck:Notes\*::*" "Front:привет\_\*" tag:reverse"#,
+        ]);
+    }
+
+    #[tokio::test]
+    async fn update_word_fails_without_notes() {
+        let anki = MockAnki::start(|request| match request["action"].as_str().unwrap() {
+            "findNotes" => ok(json!([])),
+            _ => anki(request),
+        }).await;
+
+        let err = anki.client().update_word(&book(), &word("run", "бежать"), &word("run", "бежать")).await.unwrap_err();
 
-        Ok(response.text().await?)
+        assert_eq!(err.to_string(), "No note for 'run' found in deck 'Test Book'");
     }
 }
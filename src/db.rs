@@ -1,51 +1,268 @@
+use std::collections::HashMap;
 use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{LazyLock, OnceLock};
+
+use futures::future::{BoxFuture, FutureExt};
 use tokio::fs;
-use crate::model::{Book, Word};
-use anyhow::{Context, Result};
-use log::info;
+use crate::model::{Book, BookSource, Word};
+use crate::settings::{StorageBackend, StorageSettings};
+use crate::util;
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+use log::{debug, info, warn};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-const DIR: &str = "data";
+use self::sqlite::SqliteStorage;
+
+mod sqlite;
+
+// Words used to be cached in `data/` relative to the working directory
+const LEGACY_DIR: &str = "data";
+static DATA_DIR: OnceLock<String> = OnceLock::new();
+static FILENAME_DISALLOWED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[^a-z0-9\s]").unwrap());
+static LEGACY_FILENAME_DISALLOWED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[^a-z\s]").unwrap());
+
+#[derive(Serialize)]
+struct SavedBookRef<'a> {
+    #[serde(flatten)]
+    book: &'a Book,
+    words: &'a [Word],
+}
+
+#[derive(Deserialize)]
+struct SavedBook {
+    #[serde(flatten)]
+    book: Book,
+    words: Vec<Word>,
+}
+
+// Files used to hold only the array of words, without the book metadata
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedFile {
+    Book(SavedBook),
+    Words(Vec<Word>),
+}
+
+impl SavedFile {
+    fn into_words(self) -> Vec<Word> {
+        match self {
+            SavedFile::Book(saved_book) => saved_book.words,
+            SavedFile::Words(words) => words,
+        }
+    }
+}
+
+pub trait Storage: Send + Sync {
+    fn save_words<'a>(&'a self, book: &'a Book, words: &'a [Word]) -> BoxFuture<'a, Result<()>>;
+
+    fn get_words<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, Result<Vec<Word>>>;
+
+    fn has_words<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, bool>;
+
+    fn find_book<'a>(&'a self, title: &'a str) -> BoxFuture<'a, Result<Option<Book>>>;
+
+    fn list_books(&self) -> BoxFuture<'_, Result<Vec<CachedBook>>>;
 
-pub async fn save_words(book_name: &str, words: &Vec<Word>) -> Result<()> {
-    match fs::create_dir(DIR).await {
-        Err(err) =>
-            if err.kind() != ErrorKind::AlreadyExists {
-                return Err(err).with_context(|| format!("Failed to create dir '{DIR}'"))?;
-            },
-        _ => ()
+    fn remove_book<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, Result<()>>;
+
+    fn migrate_legacy_files<'a>(&'a self, books: &'a [Book]) -> BoxFuture<'a, Result<()>>;
+}
+
+pub struct CachedBook {
+    pub book: Book,
+    pub word_count: usize,
+    pub size_bytes: u64,
+    /// Saved without the book metadata, so the title is only guessed from the file name
+    pub legacy: bool,
+}
+
+pub fn open(settings: &StorageSettings) -> Result<Box<dyn Storage>> {
+    Ok(match settings.backend {
+        StorageBackend::Json => Box::new(JsonStorage),
+        StorageBackend::Sqlite => {
+            let sqlite_path = settings.sqlite_path.clone()
+                .unwrap_or_else(|| format!("{}/words.sqlite", data_dir()));
+
+            Box::new(SqliteStorage::open(Path::new(&sqlite_path))?)
+        }
+    })
+}
+
+pub async fn init_data_dir(settings: &StorageSettings) -> Result<()> {
+    let dir = match &settings.data_dir {
+        Some(dir) => dir.to_owned(),
+        None => util::data_dir_path()?.to_string_lossy().into_owned(),
     };
 
-    let filename = get_filename(book_name);
+    migrate_legacy_dir(&dir).await?;
+    debug!("Using data dir '{dir}'");
 
-    let mut file = fs::File::create(&filename).await
-        .with_context(|| format!("Failed to create file {filename}"))?;
+    DATA_DIR.set(dir).map_err(|_| anyhow!("Data dir is already set"))
+}
 
-    let json = serde_json::to_string(words)
-        .with_context(|| format!("Failed to serialize words"))?;
+pub fn data_dir() -> &'static str {
+    DATA_DIR.get().map(String::as_str).unwrap_or(LEGACY_DIR)
+}
 
-    file.write_all(json.as_ref()).await
-        .with_context(|| format!("Failed to write contents to the file"))?;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FailedWord {
+    pub word: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FailedBook {
+    title: String,
+    words: Vec<FailedWord>,
+}
+
+pub fn failed_words_filename() -> String {
+    format!("{}/failed.json", data_dir())
+}
+
+// Every run replaces the failures of the book, so words that were fixed since drop out
+pub async fn save_failed_words(book: &Book, words: Vec<FailedWord>) -> Result<()> {
+    let filename = failed_words_filename();
+
+    let mut failed: HashMap<String, FailedBook> = match fs::read_to_string(&filename).await {
+        Ok(json) => serde_json::from_str(&json)
+            .with_context(|| format!("Couldn't deserialize failed words from file at '{filename}'"))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+        Err(err) => return Err(err).with_context(|| format!("Couldn't read failed words from '{filename}'")),
+    };
+
+    if words.is_empty() {
+        if failed.remove(&book.id.to_string()).is_none() {
+            return Ok(());
+        }
+    } else {
+        failed.insert(book.id.to_string(), FailedBook { title: book.title.to_owned(), words });
+    }
+
+    let dir = data_dir();
+    fs::create_dir_all(dir).await
+        .with_context(|| format!("Failed to create dir '{dir}'"))?;
+
+    let tmp_filename = format!("{filename}.tmp");
+    let json = serde_json::to_string_pretty(&failed)
+        .context("Failed to serialize failed words")?;
+
+    fs::write(&tmp_filename, json).await
+        .with_context(|| format!("Failed to write file {tmp_filename}"))?;
+    fs::rename(&tmp_filename, &filename).await
+        .with_context(|| format!("Failed to move {tmp_filename} to {filename}"))?;
 
     Ok(())
 }
 
-pub async fn get_words(book: &Book) -> Result<Vec<Word>> {
-    let filename = get_filename(&book.title);
-    let file_open_result = fs::File::open(&filename).await;
+// Copies the legacy dir over once, so the cache isn't rebuilt from scratch
+async fn migrate_legacy_dir(dir: &str) -> Result<()> {
+    if Path::new(dir) == Path::new(LEGACY_DIR) || !is_empty_dir(dir).await? || is_empty_dir(LEGACY_DIR).await? {
+        return Ok(());
+    }
+
+    fs::create_dir_all(dir).await
+        .with_context(|| format!("Failed to create dir '{dir}'"))?;
 
-    if file_open_result.is_err() {
-        let error = file_open_result.err().unwrap();
-        return if error.kind() == ErrorKind::NotFound {
-            info!("Words file '{filename}' doesn't exist");
-            Ok(Vec::new())
-        } else {
-            Err(error)
-                .with_context(|| format!("Couldn't open data file at '{filename}'"))
+    let mut entries = fs::read_dir(LEGACY_DIR).await
+        .with_context(|| format!("Couldn't list dir '{LEGACY_DIR}'"))?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            let target = Path::new(dir).join(entry.file_name());
+            fs::copy(entry.path(), &target).await
+                .with_context(|| format!("Failed to copy '{}' to '{}'", entry.path().display(), target.display()))?;
         }
     }
-    let mut file = file_open_result.unwrap();
+
+    info!("Copied the cached data from '{LEGACY_DIR}' to '{dir}'");
+
+    Ok(())
+}
+
+async fn is_empty_dir(dir: &str) -> Result<bool> {
+    match fs::read_dir(dir).await {
+        Ok(mut entries) => Ok(entries.next_entry().await?.is_none()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(err).with_context(|| format!("Couldn't list dir '{dir}'")),
+    }
+}
+
+pub struct JsonStorage;
+
+impl Storage for JsonStorage {
+    fn save_words<'a>(&'a self, book: &'a Book, words: &'a [Word]) -> BoxFuture<'a, Result<()>> {
+        save_words(book, words).boxed()
+    }
+
+    fn get_words<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, Result<Vec<Word>>> {
+        get_words(book).boxed()
+    }
+
+    fn has_words<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, bool> {
+        has_words(book).boxed()
+    }
+
+    fn find_book<'a>(&'a self, title: &'a str) -> BoxFuture<'a, Result<Option<Book>>> {
+        find_book(title).boxed()
+    }
+
+    fn list_books(&self) -> BoxFuture<'_, Result<Vec<CachedBook>>> {
+        list_books().boxed()
+    }
+
+    fn remove_book<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, Result<()>> {
+        remove_book(book).boxed()
+    }
+
+    fn migrate_legacy_files<'a>(&'a self, books: &'a [Book]) -> BoxFuture<'a, Result<()>> {
+        migrate_legacy_files(books).boxed()
+    }
+}
+
+async fn save_words(book: &Book, words: &[Word]) -> Result<()> {
+    let dir = data_dir();
+    fs::create_dir_all(dir).await
+        .with_context(|| format!("Failed to create dir '{dir}'"))?;
+
+    let filename = get_filename(book);
+    let tmp_filename = format!("{filename}.tmp");
+
+    let mut file = fs::File::create(&tmp_filename).await
+        .with_context(|| format!("Failed to create file {tmp_filename}"))?;
+
+    let json = serde_json::to_string(&SavedBookRef { book, words })
+        .context("Failed to serialize words")?;
+
+    file.write_all(json.as_ref()).await
+        .context("Failed to write contents to the file")?;
+    file.sync_all().await
+        .context("Failed to flush contents to the file")?;
+
+    fs::rename(&tmp_filename, &filename).await
+        .with_context(|| format!("Failed to move {tmp_filename} to {filename}"))?;
+
+    Ok(())
+}
+
+async fn get_words(book: &Book) -> Result<Vec<Word>> {
+    let filename = match existing_filename(book).await {
+        Some(filename) => filename,
+        None => {
+            info!("Words file '{}' doesn't exist", get_filename(book));
+            return Ok(Vec::new());
+        }
+    };
+
+    Ok(read_saved_file(&filename).await?.into_words())
+}
+
+async fn read_saved_file(filename: &str) -> Result<SavedFile> {
+    let mut file = fs::File::open(filename).await
+        .with_context(|| format!("Couldn't open data file at '{filename}'"))?;
 
     let mut buf = String::new();
     file.read_to_string(&mut buf).await
@@ -57,11 +274,195 @@ pub async fn get_words(book: &Book) -> Result<Vec<Word>> {
     Ok(result)
 }
 
-fn get_filename(book_name: &str) -> String {
-    let regex = Regex::new(r"[^a-z\s]").unwrap();
+async fn has_words(book: &Book) -> bool {
+    existing_filename(book).await.is_some()
+}
+
+// Files used to be named after the title only, which made different books collide, and then held only
+// the array of words. Once the Readwise books tell which book such a file belongs to, it's rewritten
+// under the current name with the book metadata.
+async fn migrate_legacy_files(books: &[Book]) -> Result<()> {
+    let dir = data_dir();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("Couldn't list dir '{dir}'")),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = entry.file_name().to_string_lossy().into_owned();
+        let Some(name) = filename.strip_suffix(".json") else { continue };
+        let path = format!("{dir}/{filename}");
+
+        // Titles without ASCII letters all had the same legacy name, made of underscores only
+        if name.trim_matches('_').is_empty() || !is_words_array(&path).await? {
+            continue;
+        }
+
+        let owners = match name.split_once('_').and_then(|(id, _)| id.parse::<u64>().ok()) {
+            Some(id) => books.iter().filter(|book| book.id == id).collect_vec(),
+            None => books.iter().filter(|book| slugify(&book.title, &LEGACY_FILENAME_DISALLOWED) == name).collect_vec(),
+        };
+
+        let book = match owners.as_slice() {
+            [] => continue,
+            [book] => book,
+            _ => {
+                warn!("Not migrating '{path}', it could belong to any of the books {}", owners.iter().join(", "));
+                continue;
+            }
+        };
+
+        let filename = get_filename(book);
+        if filename != path && fs::metadata(&filename).await.is_ok() {
+            debug!("Not migrating '{path}', '{book}' already has the words file '{filename}'");
+            continue;
+        }
+
+        let words = read_saved_file(&path).await?.into_words();
+        save_words(book, &words).await?;
+        if filename != path {
+            fs::remove_file(&path).await
+                .with_context(|| format!("Failed to remove '{path}'"))?;
+        }
+
+        info!("Migrated the cached words of '{book}' from '{path}' to '{filename}'");
+    }
+
+    Ok(())
+}
+
+// Files with the book metadata hold an object, the older ones only the array of words
+async fn is_words_array(filename: &str) -> Result<bool> {
+    let mut file = fs::File::open(filename).await
+        .with_context(|| format!("Couldn't open data file at '{filename}'"))?;
+
+    let mut first_byte = [0; 1];
+    let read = file.read(&mut first_byte).await
+        .with_context(|| format!("Couldn't read data file at '{filename}'"))?;
+
+    Ok(read == 1 && first_byte[0] == b'[')
+}
+
+async fn find_book(title: &str) -> Result<Option<Book>> {
+    let book_name = slugify(title, &FILENAME_DISALLOWED);
 
-    let book_name = regex.replace_all(&book_name.to_lowercase(), "")
-        .replace(" ", "_");
+    let dir = data_dir();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Couldn't list dir '{dir}'")),
+    };
+
+    // The slug drops every character but ASCII letters and digits, so different titles can share it
+    let mut books = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = entry.file_name().to_string_lossy().into_owned();
+        let id = filename.strip_suffix(".json")
+            .and_then(|name| name.split_once('_'))
+            .filter(|(_, name)| *name == book_name)
+            .and_then(|(id, _)| id.parse::<u64>().ok());
+
+        if let Some(id) = id {
+            match read_saved_file(&format!("{dir}/{filename}")).await? {
+                SavedFile::Book(saved_book) if saved_book.book.title.to_lowercase() == title.to_lowercase() => books.push(saved_book.book),
+                SavedFile::Book(_) => {}
+                SavedFile::Words(_) => books.push(Book { id, title: title.to_owned(), author: None, source: BookSource::default() }),
+            }
+        }
+    }
+
+    match books.len() {
+        0 => {}
+        1 => return Ok(books.pop()),
+        _ => bail!("Several cached books match the title '{title}': {}", books.iter().map(|book| book.id).join(", ")),
+    }
+
+    let legacy_book = Book { id: 0, title: title.to_owned(), author: None, source: BookSource::default() };
+    Ok(legacy_filename(&legacy_book).await.map(|_| legacy_book))
+}
+
+async fn list_books() -> Result<Vec<CachedBook>> {
+    let dir = data_dir();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Couldn't list dir '{dir}'")),
+    };
+
+    let mut books = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = entry.file_name().to_string_lossy().into_owned();
+        let Some(name) = filename.strip_suffix(".json") else { continue };
+        let path = format!("{dir}/{filename}");
+
+        // The translation and lemma caches and the failed words live in the same dir
+        let saved_file = match read_saved_file(&path).await {
+            Ok(saved_file) => saved_file,
+            Err(err) => {
+                debug!("Skipping '{path}': {err:#}");
+                continue;
+            }
+        };
+
+        let book = match saved_file {
+            SavedFile::Book(saved_book) => {
+                CachedBook { word_count: saved_book.words.len(), book: saved_book.book, size_bytes: 0, legacy: false }
+            }
+            SavedFile::Words(words) => {
+                let (id, title) = name.split_once('_')
+                    .and_then(|(id, title)| Some((id.parse::<u64>().ok()?, title)))
+                    .unwrap_or((0, name));
+                let book = Book { id, title: title.replace('_', " "), author: None, source: BookSource::default() };
+
+                CachedBook { book, word_count: words.len(), size_bytes: 0, legacy: true }
+            }
+        };
+
+        books.push(CachedBook { size_bytes: entry.metadata().await?.len(), ..book });
+    }
+
+    Ok(books)
+}
+
+async fn remove_book(book: &Book) -> Result<()> {
+    if let Some(filename) = existing_filename(book).await {
+        fs::remove_file(&filename).await
+            .with_context(|| format!("Failed to remove '{filename}'"))?;
+    }
+
+    Ok(())
+}
+
+// Files that aren't migrated yet are still read under their legacy name
+async fn existing_filename(book: &Book) -> Option<String> {
+    let filename = get_filename(book);
+    if fs::metadata(&filename).await.is_ok() {
+        return Some(filename);
+    }
+
+    legacy_filename(book).await
+}
+
+// Titles without ASCII letters all had the same legacy name, and the other caches live in the same
+// dir, so only a words array under a name with letters counts
+async fn legacy_filename(book: &Book) -> Option<String> {
+    let book_name = slugify(&book.title, &LEGACY_FILENAME_DISALLOWED);
+    if book_name.trim_matches('_').is_empty() {
+        return None;
+    }
+
+    let filename = format!("{}/{book_name}.json", data_dir());
+    is_words_array(&filename).await.unwrap_or(false).then_some(filename)
+}
+
+fn get_filename(book: &Book) -> String {
+    let book_name = slugify(&book.title, &FILENAME_DISALLOWED);
+
+    format!("{}/{}_{book_name}.json", data_dir(), book.id)
+}
 
-    format!("{DIR}/{book_name}.json")
+fn slugify(text: &str, disallowed: &Regex) -> String {
+    disallowed.replace_all(&text.to_lowercase(), "")
+        .replace(' ', "_")
 }
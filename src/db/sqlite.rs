@@ -0,0 +1,173 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::db::{CachedBook, Storage};
+use crate::model::{BookSource, Book, Word};
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create dir '{}'", dir.display()))?;
+        }
+
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open SQLite database at '{}'", path.display()))?;
+
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS books (
+                id INTEGER PRIMARY KEY,
+                title TEXT NOT NULL,
+                author TEXT
+            );
+            CREATE TABLE IF NOT EXISTS words (
+                book_id INTEGER NOT NULL REFERENCES books(id),
+                original_text TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                word TEXT NOT NULL,
+                PRIMARY KEY (book_id, original_text)
+            );"
+        ).context("Failed to create SQLite schema")?;
+
+        Ok(SqliteStorage { connection: Mutex::new(connection) })
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.connection.lock().map_err(|_| anyhow!("SQLite connection lock is poisoned"))
+    }
+
+    fn save_words_sync(&self, book: &Book, words: &[Word]) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        transaction.execute(
+            "INSERT INTO books (id, title, author) VALUES (?1, ?2, ?3)
+             ON CONFLICT(id) DO UPDATE SET title = excluded.title, author = excluded.author",
+            params![book.id as i64, book.title, book.author],
+        )?;
+        transaction.execute("DELETE FROM words WHERE book_id = ?1", params![book.id as i64])?;
+
+        for (position, word) in words.iter().enumerate() {
+            let json = serde_json::to_string(word)
+                .with_context(|| format!("Failed to serialize word '{word}'"))?;
+
+            transaction.execute(
+                "INSERT OR REPLACE INTO words (book_id, original_text, position, word) VALUES (?1, ?2, ?3, ?4)",
+                params![book.id as i64, word.original_text, position as i64, json],
+            )?;
+        }
+
+        transaction.commit()
+            .with_context(|| format!("Failed to save words for '{}'", book.title))
+    }
+
+    fn get_words_sync(&self, book: &Book) -> Result<Vec<Word>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare("SELECT word FROM words WHERE book_id = ?1 ORDER BY position")?;
+
+        let rows = statement.query_map(params![book.id as i64], |row| row.get::<_, String>(0))?;
+
+        let mut words = Vec::new();
+        for row in rows {
+            let json = row?;
+            words.push(serde_json::from_str(&json)
+                .with_context(|| format!("Couldn't deserialize word for '{}'", book.title))?);
+        }
+
+        Ok(words)
+    }
+
+    fn find_book_sync(&self, title: &str) -> Result<Option<Book>> {
+        let connection = self.connection()?;
+
+        let book = connection
+            .query_row(
+                "SELECT id, title, author FROM books WHERE title = ?1 COLLATE NOCASE",
+                params![title],
+                |row| Ok(Book { id: row.get::<_, i64>(0)? as u64, title: row.get(1)?, author: row.get(2)?, source: BookSource::default() }),
+            )
+            .optional()?;
+
+        Ok(book)
+    }
+
+    fn list_books_sync(&self) -> Result<Vec<CachedBook>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(
+            "SELECT books.id, books.title, books.author, COUNT(words.word), COALESCE(SUM(LENGTH(words.word)), 0)
+             FROM books LEFT JOIN words ON words.book_id = books.id
+             GROUP BY books.id ORDER BY books.title"
+        )?;
+
+        let rows = statement.query_map([], |row| {
+            Ok(CachedBook {
+                book: Book { id: row.get::<_, i64>(0)? as u64, title: row.get(1)?, author: row.get(2)?, source: BookSource::default() },
+                word_count: row.get::<_, i64>(3)? as usize,
+                size_bytes: row.get::<_, i64>(4)? as u64,
+                legacy: false,
+            })
+        })?;
+
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    fn remove_book_sync(&self, book: &Book) -> Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        transaction.execute("DELETE FROM words WHERE book_id = ?1", params![book.id as i64])?;
+        transaction.execute("DELETE FROM books WHERE id = ?1", params![book.id as i64])?;
+
+        transaction.commit()
+            .with_context(|| format!("Failed to remove '{}'", book.title))
+    }
+
+    fn has_words_sync(&self, book: &Book) -> Result<bool> {
+        let connection = self.connection()?;
+
+        let found = connection
+            .query_row("SELECT 1 FROM words WHERE book_id = ?1 LIMIT 1", params![book.id as i64], |_| Ok(()))
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn save_words<'a>(&'a self, book: &'a Book, words: &'a [Word]) -> BoxFuture<'a, Result<()>> {
+        async move { self.save_words_sync(book, words) }.boxed()
+    }
+
+    fn get_words<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, Result<Vec<Word>>> {
+        async move { self.get_words_sync(book) }.boxed()
+    }
+
+    fn has_words<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, bool> {
+        async move { self.has_words_sync(book).unwrap_or(false) }.boxed()
+    }
+
+    fn find_book<'a>(&'a self, title: &'a str) -> BoxFuture<'a, Result<Option<Book>>> {
+        async move { self.find_book_sync(title) }.boxed()
+    }
+
+    fn list_books(&self) -> BoxFuture<'_, Result<Vec<CachedBook>>> {
+        async move { self.list_books_sync() }.boxed()
+    }
+
+    fn remove_book<'a>(&'a self, book: &'a Book) -> BoxFuture<'a, Result<()>> {
+        async move { self.remove_book_sync(book) }.boxed()
+    }
+
+    // The database never used the legacy JSON file names
+    fn migrate_legacy_files<'a>(&'a self, _books: &'a [Book]) -> BoxFuture<'a, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+}
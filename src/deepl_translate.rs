@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use log::info;
+use reqwest::header;
+use reqwest::header::HeaderValue;
+use serde::Deserialize;
+
+use crate::settings::{EmptyTranslationPolicy, Service, TranslationSettings};
+use crate::translator::{self, Translator};
+use crate::util;
+
+// Free API keys end with ":fx" and only work with the free endpoint
+const FREE_ENDPOINT: &str = "https://api-free.deepl.com/v2/translate";
+const PRO_ENDPOINT: &str = "https://api.deepl.com/v2/translate";
+
+pub struct DeepLTranslate {
+    http: reqwest::Client,
+    endpoint: &'static str,
+    source_language: String,
+    empty_policy: EmptyTranslationPolicy,
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    auth_key: String,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    translations: Vec<TranslationResponse>,
+}
+
+#[derive(Deserialize)]
+struct TranslationResponse {
+    text: Option<String>,
+}
+
+impl DeepLTranslate {
+    pub async fn new(settings: TranslationSettings, source_language: &str) -> Result<DeepLTranslate> {
+        let creds: Credentials = util::load_credentials("deepl", &[("auth_key", "DEEPL_AUTH_KEY")]).await
+            .context("Failed to get credentials for DeepL client")?;
+
+        let mut default_headers = header::HeaderMap::new();
+        default_headers.insert("Accept", HeaderValue::from_str("application/json")?);
+        default_headers.insert("Authorization", HeaderValue::from_str(&format!("DeepL-Auth-Key {}", creds.auth_key))?);
+
+        let http = util::http_client(default_headers)?;
+        let endpoint = if creds.auth_key.ends_with(":fx") { FREE_ENDPOINT } else { PRO_ENDPOINT };
+
+        // DeepL expects upper case language codes
+        let source_language = source_language.to_uppercase();
+
+        Ok(DeepLTranslate { http, endpoint, source_language, empty_policy: settings.empty_policy })
+    }
+
+    pub async fn translate(&self, query: &str) -> Result<Option<String>> {
+        info!("DeepL query: '{query}'");
+        util::count_request(Service::Translation);
+
+        let response: Response = self.http.post(self.endpoint)
+            .form(&[("text", query), ("source_lang", &self.source_language), ("target_lang", "RU")])
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+
+        let translation = response.translations.into_iter().next()
+            .and_then(|t| t.text)
+            .filter(|text| !text.is_empty());
+
+        translator::apply_empty_policy(query, translation, self.empty_policy)
+    }
+}
+
+impl Translator for DeepLTranslate {
+    fn translate<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        self.translate(query).boxed()
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use futures::future::{BoxFuture, FutureExt};
+use itertools::Itertools;
+use log::{info, warn};
+
+use crate::model::{Provenance, Word};
+
+/// Failures that callers handle the same way for every dictionary
+#[derive(Debug)]
+pub enum DictionaryError {
+    CredentialsRejected { dictionary: &'static str, detail: String },
+    NotFound { dictionary: &'static str, word: String },
+}
+
+impl DictionaryError {
+    pub fn is_credentials_rejected(err: &anyhow::Error) -> bool {
+        matches!(err.downcast_ref::<DictionaryError>(), Some(DictionaryError::CredentialsRejected { .. }))
+    }
+
+    pub fn is_not_found(err: &anyhow::Error) -> bool {
+        matches!(err.downcast_ref::<DictionaryError>(), Some(DictionaryError::NotFound { .. }))
+    }
+}
+
+impl std::error::Error for DictionaryError {}
+
+impl std::fmt::Display for DictionaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DictionaryError::CredentialsRejected { dictionary, detail } => {
+                write!(f, "{dictionary} credentials rejected ({detail})")
+            }
+            DictionaryError::NotFound { dictionary, word } => write!(f, "{dictionary} has no entry for '{word}'"),
+        }
+    }
+}
+
+pub trait Dictionary: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn word_stem<'a>(&'a self, word: &'a str) -> BoxFuture<'a, Result<String>>;
+
+    fn definitions<'a>(&'a self, word_stem: &'a str) -> BoxFuture<'a, Result<Word>>;
+
+    // Saves what the dictionary caches in memory
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+}
+
+pub struct CompositeDictionary {
+    dictionaries: Vec<Arc<dyn Dictionary>>,
+    // Dictionaries that rejected the credentials are skipped for the rest of the run
+    rejected: Vec<AtomicBool>,
+}
+
+impl CompositeDictionary {
+    pub fn new(dictionaries: Vec<Arc<dyn Dictionary>>) -> CompositeDictionary {
+        let rejected = dictionaries.iter().map(|_| AtomicBool::new(false)).collect();
+
+        CompositeDictionary { dictionaries, rejected }
+    }
+
+    fn available(&self) -> impl Iterator<Item = (usize, &Arc<dyn Dictionary>)> {
+        self.dictionaries.iter().enumerate()
+            .filter(|(index, _)| !self.rejected[*index].load(Ordering::Relaxed))
+    }
+
+    // The other dictionaries take over, a bad key only aborts when no other dictionary is left
+    fn skip_rejected(&self, index: usize, err: &anyhow::Error) -> bool {
+        let others_left = self.available().any(|(other, _)| other != index);
+
+        if others_left && !self.rejected[index].swap(true, Ordering::Relaxed) {
+            warn!("{} rejected the credentials, skipping it from now on: {err}", self.dictionaries[index].name());
+        }
+
+        others_left
+    }
+
+    fn combine_errors(mut errors: Vec<(String, anyhow::Error)>) -> anyhow::Error {
+        if errors.len() == 1 {
+            return errors.remove(0).1;
+        }
+
+        let error_str = errors.iter()
+            .map(|(name, err)| format!("{name}: {err}"))
+            .join("\n");
+
+        // Keeping the not-found error underneath, so callers still see that no dictionary has the word
+        if errors.iter().all(|(_, err)| DictionaryError::is_not_found(err)) {
+            return errors.remove(0).1.context(format!("All dictionaries failed:\n{error_str}"));
+        }
+
+        anyhow!("All dictionaries failed:\n{error_str}")
+    }
+}
+
+impl Dictionary for CompositeDictionary {
+    fn name(&self) -> &str {
+        "composite"
+    }
+
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        async move {
+            for dictionary in &self.dictionaries {
+                dictionary.flush().await;
+            }
+        }.boxed()
+    }
+
+    fn word_stem<'a>(&'a self, word: &'a str) -> BoxFuture<'a, Result<String>> {
+        async move {
+            let mut errors = Vec::new();
+
+            for (index, dictionary) in self.available() {
+                match dictionary.word_stem(word).await {
+                    Ok(word_stem) => return Ok(word_stem),
+                    Err(err) if DictionaryError::is_credentials_rejected(&err) => {
+                        if !self.skip_rejected(index, &err) {
+                            return Err(err);
+                        }
+                    }
+                    Err(err) => errors.push((dictionary.name().to_owned(), err)),
+                }
+            }
+
+            Err(Self::combine_errors(errors))
+        }.boxed()
+    }
+
+    fn definitions<'a>(&'a self, word_stem: &'a str) -> BoxFuture<'a, Result<Word>> {
+        async move {
+            let mut errors = Vec::new();
+
+            for (index, dictionary) in self.available() {
+                match dictionary.definitions(word_stem).await {
+                    Ok(mut word) => {
+                        info!("Definitions for '{word_stem}' provided by {}", dictionary.name());
+                        word.provenance.get_or_insert_with(Provenance::default).dictionary =
+                            Some(dictionary.name().to_owned());
+                        return Ok(word);
+                    }
+                    Err(err) if DictionaryError::is_credentials_rejected(&err) => {
+                        if !self.skip_rejected(index, &err) {
+                            return Err(err);
+                        }
+                    }
+                    Err(err) => {
+                        warn!("{} failed to define '{word_stem}': {err}", dictionary.name());
+                        errors.push((dictionary.name().to_owned(), err));
+                    }
+                }
+            }
+
+            Err(Self::combine_errors(errors))
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    struct FakeDictionary {
+        name: &'static str,
+        error: Option<fn() -> DictionaryError>,
+        lookups: AtomicUsize,
+    }
+
+    impl FakeDictionary {
+        fn new(name: &'static str, error: Option<fn() -> DictionaryError>) -> Arc<FakeDictionary> {
+            Arc::new(FakeDictionary { name, error, lookups: AtomicUsize::new(0) })
+        }
+    }
+
+    impl Dictionary for FakeDictionary {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn word_stem<'a>(&'a self, word: &'a str) -> BoxFuture<'a, Result<String>> {
+            async move { Ok(word.to_owned()) }.boxed()
+        }
+
+        fn definitions<'a>(&'a self, word_stem: &'a str) -> BoxFuture<'a, Result<Word>> {
+            async move {
+                self.lookups.fetch_add(1, Ordering::Relaxed);
+
+                match self.error {
+                    Some(error) => Err(error())?,
+                    None => Ok(Word::from_text(word_stem)),
+                }
+            }.boxed()
+        }
+    }
+
+    fn rejected() -> DictionaryError {
+        DictionaryError::CredentialsRejected { dictionary: "Fake", detail: "401 Unauthorized".to_owned() }
+    }
+
+    fn not_found() -> DictionaryError {
+        DictionaryError::NotFound { dictionary: "Fake", word: "run".to_owned() }
+    }
+
+    #[tokio::test]
+    async fn skips_dictionary_that_rejected_credentials() {
+        let oxford = FakeDictionary::new("oxford", Some(rejected));
+        let wiktionary = FakeDictionary::new("wiktionary", None);
+        let dictionary = CompositeDictionary::new(vec![oxford.clone(), wiktionary.clone()]);
+
+        for _ in 0..2 {
+            let word = dictionary.definitions("run").await.unwrap();
+            assert_eq!(word.provenance.unwrap().dictionary.as_deref(), Some("wiktionary"));
+        }
+
+        assert_eq!(oxford.lookups.load(Ordering::Relaxed), 1);
+        assert_eq!(wiktionary.lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn last_dictionary_rejecting_credentials_fails() {
+        let dictionary = CompositeDictionary::new(vec![FakeDictionary::new("oxford", Some(rejected))]);
+
+        for _ in 0..2 {
+            let err = dictionary.definitions("run").await.unwrap_err();
+            assert!(DictionaryError::is_credentials_rejected(&err));
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_not_found_when_no_dictionary_has_the_word() {
+        let dictionary = CompositeDictionary::new(vec![
+            FakeDictionary::new("oxford", Some(not_found)),
+            FakeDictionary::new("wiktionary", Some(not_found)),
+        ]);
+
+        let err = dictionary.definitions("run").await.unwrap_err();
+        assert!(DictionaryError::is_not_found(&err));
+        assert!(err.to_string().starts_with("All dictionaries failed:"));
+    }
+}
@@ -0,0 +1,107 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+
+use crate::model::{Book, Word};
+use crate::render::CardRenderer;
+
+// Keeps re-exported decks of the same book mergeable on import
+const DECK_ID_BASE: i64 = 1_700_000_000_000;
+// Far above the Readwise ids, so decks identified by title never collide with those identified by id
+const TITLE_DECK_ID_BASE: i64 = 5_000_000_000_000;
+
+pub fn write_csv(words: &[Word], output: &Path) -> Result<()> {
+    let mut writer = csv::Writer::from_path(output)
+        .with_context(|| format!("Failed to create CSV file '{}'", output.display()))?;
+
+    writer.write_record(["original_text", "stem", "translation", "definitions"])?;
+
+    for word in words {
+        writer.write_record([
+            word.original_text.as_str(),
+            word.text.as_str(),
+            word.translation.as_deref().unwrap_or_default(),
+            &flatten_definitions(word),
+        ])?;
+    }
+
+    writer.flush()
+        .with_context(|| format!("Failed to write CSV file '{}'", output.display()))?;
+
+    Ok(())
+}
+
+fn flatten_definitions(word: &Word) -> String {
+    word.definitions.iter()
+        .flatten()
+        .sorted_by_key(|(category, _)| *category)
+        .map(|(category, definitions)| {
+            let definitions = definitions.iter()
+                .filter_map(|definition| {
+                    let text = definition.definition.as_ref()?;
+
+                    Some(if definition.examples.is_empty() {
+                        text.to_owned()
+                    } else {
+                        format!("{text} (e.g. {})", definition.examples.join("; "))
+                    })
+                })
+                .join(" | ");
+
+            format!("{category}: {definitions}")
+        })
+        .join("\n")
+}
+
+pub fn write_apkg(book: &Book, deck_name: &str, words: &[Word], renderer: &CardRenderer, output: &Path) -> Result<()> {
+    let mut deck = genanki_rs::Deck::new(deck_id(book), deck_name, "");
+
+    for word in words {
+        let back = renderer.back_html(word)?;
+        let note = genanki_rs::Note::new(genanki_rs::basic_model(), vec![&word.text, &back])
+            .with_context(|| format!("Failed to create note for '{word}'"))?;
+
+        deck.add_note(note);
+    }
+
+    deck.write_to_file(&output.to_string_lossy())
+        .with_context(|| format!("Failed to write Anki package '{}'", output.display()))?;
+
+    Ok(())
+}
+
+// Books without a Readwise id would all share one deck id, so theirs comes from the title
+fn deck_id(book: &Book) -> i64 {
+    if book.id != 0 {
+        return DECK_ID_BASE + book.id as i64;
+    }
+
+    // FNV-1a, which unlike the std hasher stays the same across Rust releases
+    let hash = book.title.to_lowercase().bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+
+    TITLE_DECK_ID_BASE + (hash % 1_000_000_000_000) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::BookSource;
+
+    fn book(id: u64, title: &str) -> Book {
+        Book { id, title: title.to_string(), author: None, source: BookSource::Readwise }
+    }
+
+    #[test]
+    fn deck_id_from_book_id() {
+        assert_eq!(deck_id(&book(42, "Dune")), DECK_ID_BASE + 42);
+    }
+
+    #[test]
+    fn deck_id_from_title_without_book_id() {
+        assert_eq!(deck_id(&book(0, "Dune")), deck_id(&book(0, "dune")));
+        assert_ne!(deck_id(&book(0, "Dune")), deck_id(&book(0, "Emma")));
+        assert!(deck_id(&book(0, "Dune")) >= TITLE_DECK_ID_BASE);
+    }
+}
@@ -1,14 +1,19 @@
 use std::fmt;
+use std::path::Path;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use chrono::DateTime;
 use chrono::offset::Utc;
+use log::{debug, warn};
+use reqwest::header::HeaderMap;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tokio::fs;
 
 use crate::util;
 
 const AUTH_ENDPOINT: &str = "https://oauth2.googleapis.com/token";
+const TOKEN_CACHE_FILE: &str = "google_token.json";
 
 #[derive(Serialize, Deserialize)]
 pub struct ApplicationCredentials {
@@ -25,17 +30,24 @@ pub struct ApplicationCredentials {
     pub client_x509_cert_url: String,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 enum TokenValue {
     Bearer(String),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct Token {
     value: TokenValue,
     expiry: DateTime<Utc>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    client_email: String,
+    scopes: String,
+    token: Token,
+}
+
 impl fmt::Display for TokenValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -47,6 +59,7 @@ impl fmt::Display for TokenValue {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct AuthResponse {
     access_token: String,
+    expires_in: Option<i64>,
 }
 
 pub struct TokenManager {
@@ -54,42 +67,86 @@ pub struct TokenManager {
     scopes: String,
     creds: ApplicationCredentials,
     current_token: Option<Token>,
+    renewal_margin: chrono::Duration,
 }
 
 impl TokenManager {
-    pub async fn new(scopes: &[&str]) -> Result<TokenManager> {
+    pub async fn new(scopes: &[&str], renewal_margin: chrono::Duration) -> Result<TokenManager> {
         let creds = TokenManager::load_creds().await?;
 
-        let http = reqwest::Client::builder()
-            .connection_verbose(true)
-            .build()?;
+        let http = util::http_client(HeaderMap::new())?;
+
+        let scopes = scopes.join(" ");
+        let current_token = TokenManager::load_cached_token(&creds, &scopes).await;
 
         Ok(TokenManager {
             creds,
             http,
-            scopes: scopes.join(" "),
-            current_token: None,
+            scopes,
+            current_token,
+            renewal_margin,
         })
     }
 
+    async fn load_cached_token(creds: &ApplicationCredentials, scopes: &str) -> Option<Token> {
+        let path = util::cache_file_path(TOKEN_CACHE_FILE).ok()?;
+        let json = fs::read_to_string(&path).await.ok()?;
+
+        match serde_json::from_str::<CachedToken>(&json) {
+            Ok(cached) if cached.client_email == creds.client_email && cached.scopes == scopes => {
+                debug!("Loaded cached Google token expiring at {}", cached.token.expiry);
+                Some(cached.token)
+            }
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Ignoring unreadable cached Google token at '{}': {err}", path.display());
+                None
+            }
+        }
+    }
+
+    async fn save_token(&self, token: &Token) -> Result<()> {
+        let path = util::cache_file_path(TOKEN_CACHE_FILE)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await
+                .with_context(|| format!("Failed to create dir '{}'", dir.display()))?;
+        }
+
+        let cached = CachedToken {
+            client_email: self.creds.client_email.to_owned(),
+            scopes: self.scopes.to_owned(),
+            token: token.clone(),
+        };
+
+        util::write_private_file(&path, serde_json::to_string(&cached)?).await
+            .with_context(|| format!("Failed to write Google token to '{}'", path.display()))
+    }
+
     async fn load_creds() -> Result<ApplicationCredentials> {
-        util::load_json_config("google_translate").await
-            .with_context(|| "Failed to load JSON config for 'google-translate'")
+        if let Some(creds) = util::load_optional_json_config("google_translate").await? {
+            return Ok(creds);
+        }
+
+        match std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            Some(path) => util::load_json_file(Path::new(&path)).await
+                .with_context(|| "Failed to load Google credentials from GOOGLE_APPLICATION_CREDENTIALS"),
+            None => bail!("Neither a filled in '{}' config file nor GOOGLE_APPLICATION_CREDENTIALS is set",
+                util::config_file_path("google_translate")?.display()),
+        }
     }
 
     pub async fn token(&mut self) -> Result<String> {
-        let hour = chrono::Duration::minutes(45);
+        let assertion_lifetime = chrono::Duration::hours(1);
         let current_time = Utc::now();
 
         match self.current_token {
-            Some(ref token) if token.expiry >= current_time => Ok(token.value.to_string()),
+            Some(ref token) if token.expiry - self.renewal_margin > current_time => Ok(token.value.to_string()),
             _ => {
-                let expiry = current_time + hour;
                 let claims = json!({
                     "iss": self.creds.client_email.as_str(),
                     "scope": self.scopes.as_str(),
                     "aud": AUTH_ENDPOINT,
-                    "exp": expiry.timestamp(),
+                    "exp": (current_time + assertion_lifetime).timestamp(),
                     "iat": current_time.timestamp()
                 });
 
@@ -112,11 +169,20 @@ impl TokenManager {
                     .json()
                     .await?;
 
+                let expiry = current_time + response.expires_in
+                    .map(chrono::Duration::seconds)
+                    .unwrap_or(assertion_lifetime);
                 let value = TokenValue::Bearer(response.access_token);
-                let token = value.to_string();
-                self.current_token = Some(Token { expiry, value });
+                let token = Token { expiry, value };
+
+                if let Err(err) = self.save_token(&token).await {
+                    warn!("Failed to cache Google token: {err}");
+                }
+
+                let value = token.value.to_string();
+                self.current_token = Some(token);
 
-                Ok(token)
+                Ok(value)
             }
         }
     }
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
+use log::info;
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+
+use crate::settings::{EmptyTranslationPolicy, Service, TranslationSettings};
+use crate::translator::{self, Translator};
+use crate::util;
+
+// Unofficial endpoint used by the Google Translate web widgets. It needs no credentials, but it is
+// undocumented, rate limited per IP and its response format may change without notice.
+const ENDPOINT: &str = "https://translate.googleapis.com/translate_a/single";
+
+pub struct GoogleFreeTranslate {
+    http: reqwest::Client,
+    source_language: String,
+    empty_policy: EmptyTranslationPolicy,
+}
+
+impl GoogleFreeTranslate {
+    pub fn new(settings: TranslationSettings, source_language: &str) -> Result<GoogleFreeTranslate> {
+        let http = util::http_client(HeaderMap::new())?;
+
+        Ok(GoogleFreeTranslate { http, source_language: source_language.to_string(), empty_policy: settings.empty_policy })
+    }
+
+    pub async fn translate(&self, query: &str) -> Result<Option<String>> {
+        info!("Free Google translate query: '{query}'");
+        util::count_request(Service::Translation);
+
+        let response: Value = self.http.get(ENDPOINT)
+            .query(&[("client", "gtx"), ("sl", &self.source_language), ("tl", "ru"), ("dt", "t"), ("q", query)])
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+
+        // The response is a nested array, the first element holds the translated segments
+        let translation = response.get(0)
+            .and_then(Value::as_array)
+            .map(|segments| segments.iter()
+                .filter_map(|segment| segment.get(0).and_then(Value::as_str))
+                .collect::<String>())
+            .filter(|translation| !translation.is_empty());
+
+        translator::apply_empty_policy(query, translation, self.empty_policy)
+    }
+}
+
+impl Translator for GoogleFreeTranslate {
+    fn translate<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        self.translate(query).boxed()
+    }
+}
@@ -1,22 +1,31 @@
-use anyhow::{anyhow, Context};
+use anyhow::{bail, Context};
 use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
 use log::info;
 use reqwest::header;
 use reqwest::header::HeaderValue;
 use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 
 use crate::google_auth::TokenManager;
+use crate::settings::{EmptyTranslationPolicy, Service, TranslationSettings};
+use crate::translator::{self, Translator};
+use crate::util;
 
 const ENDPOINT: &str = "https://translation.googleapis.com/language/translate/v2";
-const SCOPE: &str = "https://www.googleapis.com/auth/cloud-translation";
+pub const SCOPE: &str = "https://www.googleapis.com/auth/cloud-translation";
+const BATCH_SIZE: usize = 100;
 
 pub struct GoogleTranslate {
     http: reqwest::Client,
+    token_manager: Mutex<TokenManager>,
+    source_language: String,
+    empty_policy: EmptyTranslationPolicy,
 }
 
 #[derive(Serialize, Deserialize)]
 struct Request {
-    q: String,
+    q: Vec<String>,
     source: String,
     target: String,
     format: String,
@@ -39,10 +48,10 @@ struct TranslationResponse {
 }
 
 impl Request {
-    fn new(query: &str) -> Request {
+    fn new(queries: Vec<String>, source_language: &str) -> Request {
         Request {
-            q: query.to_string(),
-            source: "en".to_string(),
+            q: queries,
+            source: source_language.to_string(),
             target: "ru".to_string(),
             format: "text".to_string(),
         }
@@ -50,39 +59,78 @@ impl Request {
 }
 
 impl GoogleTranslate {
-    pub async fn new() -> Result<GoogleTranslate> {
+    pub async fn new(settings: TranslationSettings, source_language: &str) -> Result<GoogleTranslate> {
         let scopes = [SCOPE];
-        let token = TokenManager::new(&scopes).await
-            .with_context(|| "Failed to create Google Token Manager")?
-            .token().await?;
+        let renewal_margin = chrono::Duration::seconds(settings.token_renewal_margin_secs);
+        let mut token_manager = TokenManager::new(&scopes, renewal_margin).await
+            .with_context(|| "Failed to create Google Token Manager")?;
+        token_manager.token().await?;
 
         let mut default_headers = header::HeaderMap::new();
         default_headers.insert("Accept", HeaderValue::from_str("application/json")?);
         default_headers.insert("Content-Type", HeaderValue::from_str("application/json")?);
-        default_headers.insert("Authorization", HeaderValue::from_str(&token)?);
 
-        let http = reqwest::Client::builder()
-            .default_headers(default_headers)
-            .connection_verbose(true)
-            .build()?;
+        let http = util::http_client(default_headers)?;
 
-        Ok(GoogleTranslate { http })
+        Ok(GoogleTranslate {
+            http,
+            token_manager: Mutex::new(token_manager),
+            source_language: source_language.to_string(),
+            empty_policy: settings.empty_policy,
+        })
     }
 
-    pub async fn translate(&self, query: &str) -> Result<String> {
-        let request = Request::new(query);
-        let body = serde_json::to_string(&request)?;
-
+    pub async fn translate(&self, query: &str) -> Result<Option<String>> {
         info!("Google translate query: '{query}'");
 
+        let translation = self.request(vec![query.to_string()]).await?
+            .into_iter().next()
+            .flatten();
+
+        translator::apply_empty_policy(query, translation, self.empty_policy)
+    }
+
+    pub async fn translate_batch(&self, queries: &[String]) -> Result<Vec<Option<String>>> {
+        let mut translations = Vec::with_capacity(queries.len());
+
+        for chunk in queries.chunks(BATCH_SIZE) {
+            info!("Google translate batch query of {} words", chunk.len());
+
+            translations.extend(self.request(chunk.to_vec()).await?);
+        }
+
+        Ok(translations)
+    }
+
+    async fn request(&self, queries: Vec<String>) -> Result<Vec<Option<String>>> {
+        let count = queries.len();
+        let body = serde_json::to_string(&Request::new(queries, &self.source_language))?;
+        let token = self.token_manager.lock().await.token().await?;
+        util::count_request(Service::Translation);
+
         let response: Response = self.http.post(ENDPOINT)
+            .header(header::AUTHORIZATION, token)
             .body(body)
             .send().await?
             .json().await?;
 
-        let translation = response.data.translations.into_iter().next();
-        translation.map(|t| t.translated_text)
-            .flatten()
-            .ok_or(anyhow!("No translation"))
+        let translations = response.data.translations;
+        if translations.len() != count {
+            bail!("Expected {count} translations, got {}", translations.len());
+        }
+
+        Ok(translations.into_iter()
+            .map(|t| t.translated_text.filter(|text| !text.is_empty()))
+            .collect())
+    }
+}
+
+impl Translator for GoogleTranslate {
+    fn translate<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        self.translate(query).boxed()
+    }
+
+    fn translate_batch<'a>(&'a self, queries: &'a [String]) -> BoxFuture<'a, Result<Vec<Option<String>>>> {
+        self.translate_batch(queries).boxed()
     }
 }
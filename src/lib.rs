@@ -0,0 +1,30 @@
+//! Imports words highlighted in Readwise into Anki, with Oxford or Wiktionary definitions
+//! and translations.
+//!
+//! [`WordProcessor`] is the entry point: `readwise()` lists the books, `process_book` imports
+//! a book into Anki, `process_word` defines a single word and `render_card` renders it.
+//!
+//! The data dir and the HTTP settings are process-wide, so call [`db::init_data_dir`] and
+//! [`util::init_http`] once before creating a [`WordProcessor`] or any client. Without them the cache
+//! falls back to `data/` in the working directory and the clients use the default network settings,
+//! which `init_http` can't change afterwards.
+
+pub mod anki_connect;
+pub mod db;
+pub mod deepl_translate;
+pub mod dictionary;
+pub mod export;
+pub mod google_auth;
+pub mod google_free_translate;
+pub mod google_translate;
+pub mod model;
+pub mod oxford_dict;
+pub mod readwise;
+pub mod render;
+pub mod settings;
+pub mod translator;
+pub mod util;
+pub mod wiktionary;
+mod word_processor;
+
+pub use word_processor::{BookSelection, WordProcessor};
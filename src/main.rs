@@ -1,211 +1,502 @@
-use std::collections::HashMap;
-use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{ArgAction, Parser, Subcommand};
 use env_logger::Env;
-use futures::try_join;
-use futures::future::join_all;
-use inquire::{MultiSelect, Select, Text};
-use itertools::{Itertools, process_results};
-use log::{debug, error, info};
-
-use crate::anki_connect::AnkiConnectClient;
-use crate::google_translate::GoogleTranslate;
-use crate::model::{Book, Word};
-use crate::oxford_dict::OxfordDictClient;
-use crate::readwise::ReadwiseClient;
-
-mod anki_connect;
-mod db;
-mod google_auth;
-mod google_translate;
-mod model;
-mod oxford_dict;
-mod readwise;
-mod util;
+use log::{debug, error, info, LevelFilter};
+use serde_json::json;
+
+use anki_words_rust::anki_connect::AnkiConnectClient;
+use anki_words_rust::db::{self, Storage};
+use anki_words_rust::google_auth::TokenManager;
+use anki_words_rust::model::{Book, Word};
+use anki_words_rust::oxford_dict::OxfordDictClient;
+use anki_words_rust::readwise::ReadwiseClient;
+use anki_words_rust::render::CardRenderer;
+use anki_words_rust::settings::{Preset, RenderingSettings, Settings, TranslationProvider, TranslationSettings};
+use anki_words_rust::{export, google_translate, translator, util, BookSelection, WordProcessor};
 
 #[derive(Parser, Debug)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
+    /// Card style: `minimal` (translation and one definition), `full` or `rich` (also audio, word family and thesaurus)
+    #[arg(long, global = true)]
+    preset: Option<Preset>,
+    /// Translate through the unofficial Google Translate web endpoint, which needs no credentials.
+    /// It is undocumented and rate limited, so expect failures on large books
+    #[arg(long, global = true)]
+    free_translate: bool,
+    /// Always request fresh translations and lemmas instead of reusing the cached ones
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Directory for the cached words, translations and lemmas, instead of the user data dir
+    #[arg(long, global = true)]
+    data_dir: Option<String>,
+    /// Delete Anki decks without asking for confirmation, required when not running in a terminal
+    #[arg(short, long, global = true)]
+    yes: bool,
+    /// Log more details, `-vv` also logs every request
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Log only warnings, `-qq` only errors
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    quiet: u8,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    ProcessWord { word: String },
-    ProcessAll { force: Option<bool> },
+    ProcessWord {
+        word: String,
+        /// Print the rendered card instead of only logging the word
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the word as JSON instead of logging it
+        #[arg(long, conflicts_with = "dry_run")]
+        json: bool,
+        /// Add the word to this Anki deck
+        #[arg(long, conflicts_with = "dry_run")]
+        deck: Option<String>,
+        /// Save the word into the cached words of the book with this title
+        #[arg(long, conflicts_with = "dry_run")]
+        book_title: Option<String>,
+    },
+    ProcessAll {
+        force: Option<bool>,
+        /// Reprocess only these cached words, e.g. `run,give,take`, and update their cards
+        #[arg(long, value_delimiter = ',', conflicts_with_all = ["force", "all_books", "only_new_books", "offline"])]
+        force_words: Vec<String>,
+        /// Print the rendered cards instead of saving words and storing them in Anki
+        #[arg(long)]
+        dry_run: bool,
+        /// Process every Readwise book instead of prompting for one
+        #[arg(long)]
+        all_books: bool,
+        /// Process only the books that don't have cached words yet
+        #[arg(long)]
+        only_new_books: bool,
+        /// Process the book with this Readwise id instead of prompting
+        #[arg(long, conflicts_with_all = ["all_books", "only_new_books", "book_title"])]
+        book_id: Option<u64>,
+        /// Process the book with this title instead of prompting
+        #[arg(long, conflicts_with_all = ["all_books", "only_new_books"])]
+        book_title: Option<String>,
+        /// Number of words processed at the same time
+        #[arg(long)]
+        concurrency: Option<usize>,
+        /// Report words that already exist in other Anki decks
+        #[arg(long)]
+        dedupe_across_decks: bool,
+        /// Skip words that already exist in other Anki decks
+        #[arg(long)]
+        global_dedupe: bool,
+        /// Drop definitions that aren't one of the core parts of speech
+        #[arg(long)]
+        standard_categories_only: bool,
+        /// Reprocess cached words that are missing a translation or definitions
+        #[arg(long)]
+        retry_incomplete: bool,
+        /// Process at most this many uncached words per book
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Store the cached words in Anki without using Readwise, Oxford or the translator
+        #[arg(long)]
+        offline: bool,
+        /// Skip words that were already imported from another book
+        #[arg(long)]
+        skip_known: bool,
+        /// Create one card per lemma, recording the other highlighted forms as its inflections
+        #[arg(long)]
+        group_by_lemma: bool,
+        /// Prompt to redact the words that failed instead of recording them in failed.json
+        #[arg(long)]
+        interactive: bool,
+        /// Log the progress every N words when the progress bar isn't shown
+        #[arg(long)]
+        log_interval: Option<usize>,
+        /// Sync the Anki collection to AnkiWeb after storing each book
+        #[arg(long)]
+        sync: bool,
+        /// Store the cards in this Anki deck instead of one named after the book
+        #[arg(long, conflicts_with_all = ["all_books", "only_new_books"])]
+        deck: Option<String>,
+        /// Only import highlights made after this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_parser = parse_date)]
+        since: Option<DateTime<Utc>>,
+        /// Only import highlights made before this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_parser = parse_date)]
+        until: Option<DateTime<Utc>>,
+    },
+    ListBooks {
+        /// Also count the words tagged for import in each book
+        #[arg(long)]
+        with_word_count: bool,
+    },
+    ExportHtml { book_title: String, output: PathBuf },
+    ExportCsv { book_title: String, output: PathBuf },
+    ExportApkg { book_title: String, output: PathBuf },
+    ReprocessWord { book_title: String, word: String },
+    /// List the books with cached words
+    ListCached,
+    /// Remove the cached words of a book
+    RemoveCached {
+        book_title: String,
+        /// Also delete the book's Anki deck
+        #[arg(long)]
+        delete_deck: bool,
+    },
+    /// Store the cached words of a book in Anki again, without Readwise, Oxford or the translator
+    RestoreToAnki {
+        /// Title of the cached book, prompts for one when missing
+        #[arg(conflicts_with = "all")]
+        book_title: Option<String>,
+        /// Restore every cached book
+        #[arg(long)]
+        all: bool,
+    },
+    /// Create template config files with placeholder credentials
+    Init,
+    /// Check the credentials and connection of every integration
+    Check,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-
     let args = Args::parse();
 
+    let mut logger = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    let level = match (args.verbose, args.quiet) {
+        (0, 0) => None,
+        (1, 0) => Some(LevelFilter::Debug),
+        (_, 0) => Some(LevelFilter::Trace),
+        (0, 1) => Some(LevelFilter::Warn),
+        _ => Some(LevelFilter::Error),
+    };
+    if let Some(level) = level {
+        logger.filter_level(level);
+    }
+    logger.init();
+    let mut settings = Settings::load().await?;
+
+    if let Some(preset) = args.preset {
+        settings.apply_preset(preset);
+    }
+    if args.free_translate {
+        settings.translation.provider = TranslationProvider::GoogleFree;
+    }
+    if args.data_dir.is_some() {
+        settings.storage.data_dir = args.data_dir.clone();
+    }
+    settings.processing.assume_yes = args.yes;
+    if args.no_cache {
+        settings.translation.cache = false;
+        settings.oxford.lemma_cache = false;
+    }
+
+    db::init_data_dir(&settings.storage).await?;
+    util::init_http(&settings.network)?;
+
     match &args.command {
-        Commands::ProcessWord { word } => {
+        Commands::ProcessWord { word, dry_run, json, deck, book_title } => {
             debug!("Defining word: '{word}'");
 
             let mut word = Word::from_text(word);
 
-            let result = WordProcessor::new().await?
-                .process_word(&mut word).await;
+            let word_processor = WordProcessor::new(&settings)?;
+            let result = word_processor.process_word(&mut word).await;
 
             match result {
-                Ok(word) => info!("Definition: {:?}", word),
+                Ok(()) => {
+                    if *json {
+                        println!("{}", serde_json::to_string_pretty(&word)?);
+                    } else {
+                        info!("Definition: {:?}", word);
+                    }
+
+                    if *dry_run {
+                        word_processor.print_cards(std::slice::from_ref(&word))?;
+                    } else if deck.is_some() || book_title.is_some() {
+                        word_processor.add_word(word, deck.as_deref(), book_title.as_deref()).await?;
+                    }
+                }
                 Err(err) => error!("Error: {err}")
             }
         }
 
-        Commands::ProcessAll { force } => {
+        Commands::ProcessAll {
+            force, force_words, dry_run, all_books, only_new_books, book_id, book_title, concurrency, dedupe_across_decks,
+            global_dedupe, standard_categories_only, retry_incomplete, limit, offline, skip_known, group_by_lemma,
+            interactive, log_interval, sync, deck, since, until
+        } => {
             debug!("Processing all words");
 
-            let word_processor = WordProcessor::new().await?;
+            if let Some(concurrency) = concurrency {
+                settings.processing.concurrency = *concurrency;
+            }
+            settings.anki.dedupe_across_decks |= dedupe_across_decks;
+            settings.anki.global_dedupe |= global_dedupe;
+            settings.anki.sync |= sync;
+            if deck.is_some() {
+                settings.anki.deck_name = deck.clone();
+            }
+            settings.readwise.since = *since;
+            settings.readwise.until = *until;
+            settings.oxford.standard_categories_only |= standard_categories_only;
+            settings.processing.retry_incomplete |= retry_incomplete;
+            settings.processing.skip_known |= skip_known;
+            settings.processing.group_by_lemma |= group_by_lemma;
+            settings.processing.interactive |= interactive;
+            settings.processing.offline |= offline;
+            if limit.is_some() {
+                settings.processing.limit = *limit;
+            }
+            if let Some(log_interval) = log_interval {
+                settings.processing.log_interval = *log_interval;
+            }
+
+            let selection = if let Some(book_id) = book_id {
+                BookSelection::Id(*book_id)
+            } else if let Some(book_title) = book_title {
+                BookSelection::Title(book_title.to_owned())
+            } else if *only_new_books {
+                BookSelection::OnlyNew
+            } else if *all_books {
+                BookSelection::All
+            } else {
+                BookSelection::Interactive
+            };
+
+            let word_processor = WordProcessor::new(&settings)?;
 
-            match word_processor.process(force.unwrap_or(false)).await {
+            let result = if force_words.is_empty() {
+                word_processor.process(force.unwrap_or(false), selection, *dry_run).await
+            } else {
+                word_processor.reprocess_words(selection, force_words, *dry_run).await
+            };
+
+            match &result {
                 Ok(_) => debug!("Finished."),
                 Err(err) => error!("Global error: {}", err)
             }
+
+            util::log_request_counts();
+
+            if result.is_err() {
+                std::process::exit(1);
+            }
         }
-    }
 
-    Ok(())
-}
+        Commands::ListBooks { with_word_count } => {
+            let readwise = ReadwiseClient::new(settings.readwise.clone()).await?;
 
-struct WordProcessor {
-    readwise: ReadwiseClient,
-    oxford_dict: OxfordDictClient,
-    google_translate: GoogleTranslate,
-    anki: AnkiConnectClient,
-}
+            let mut books = readwise.get_books().await?;
+            books.sort();
 
-impl WordProcessor {
-    pub async fn new() -> Result<WordProcessor> {
-        let (readwise, oxford_dict, google_translate) = try_join!(
-            ReadwiseClient::new(),
-            OxfordDictClient::new(),
-            GoogleTranslate::new()
-        )?;
-
-        Ok(WordProcessor {
-            readwise,
-            oxford_dict,
-            google_translate,
-            anki: AnkiConnectClient::new()?,
-        })
-    }
+            for book in books {
+                let author = book.author.as_deref().unwrap_or("N/A");
 
-    pub async fn process(&self, force: bool) -> Result<()> {
-        let mut books = self.readwise.get_books().await?;
-        books.sort();
-        let book = Self::select_book(books)?;
+                if *with_word_count {
+                    let word_count = readwise.get_words(&book).await?.len();
+                    println!("{}\t{author}\t{}\t{word_count}", book.id, book.title);
+                } else {
+                    println!("{}\t{author}\t{}", book.id, book.title);
+                }
+            }
+        }
 
-        let all_words = self.readwise.get_words(&book).await?;
-        let processed_words = self.process_words_v2(&book, all_words, force).await?;
+        Commands::ExportHtml { book_title, output } => {
+            let storage = db::open(&settings.storage)?;
+            let (book, words) = load_cached_book(storage.as_ref(), book_title).await?;
 
-        db::save_words(&book.title, &processed_words).await?;
+            let html = CardRenderer::new(settings.rendering.clone())?.study_sheet_html(&book, &words)?;
+            tokio::fs::write(output, html).await
+                .with_context(|| format!("Failed to write study sheet to '{}'", output.display()))?;
 
-        self.anki.store_book(&book, &processed_words, force).await?;
+            info!("Exported {} words of '{book}' to '{}'", words.len(), output.display());
+        }
 
-        Ok(())
-    }
+        Commands::ExportCsv { book_title, output } => {
+            let storage = db::open(&settings.storage)?;
+            let (book, words) = load_cached_book(storage.as_ref(), book_title).await?;
 
-    async fn process_words_v2(&self, book: &Book, all_words: Vec<Word>, force: bool) -> Result<Vec<Word>> {
-        let (mut unprocessed_words, mut processed_words) = if !force {
-            Self::partition_by_processed(&book, all_words).await?
-        } else {
-            (all_words, Vec::new())
-        };
+            export::write_csv(&words, output)?;
 
-        let mut count = 0;
-        while !unprocessed_words.is_empty() {
-            let mut failed_words: Vec<Word> = Vec::new();
+            info!("Exported {} words of '{book}' to '{}'", words.len(), output.display());
+        }
 
-            for mut word in unprocessed_words {
-                let result = self.process_word(&mut word).await;
+        Commands::ExportApkg { book_title, output } => {
+            let storage = db::open(&settings.storage)?;
+            let (book, words) = load_cached_book(storage.as_ref(), book_title).await?;
 
-                match result {
-                    Ok(()) => processed_words.push(word),
-                    Err(err) => {
-                        error!("Failed to process word '{word}': {err}");
-                        failed_words.push(word);
-                    }
-                };
+            // The audio files only live in Anki's media folder, the package would reference missing files
+            let renderer = CardRenderer::new(RenderingSettings { show_audio: false, ..settings.rendering.clone() })?;
+            export::write_apkg(&book, &settings.anki.deck_name(&book), &words, &renderer, output)?;
 
-                count += 1;
-                if count % 10 == 0 {
-                    info!("Processed {count} words");
-                }
-            }
+            info!("Exported {} words of '{book}' to '{}'", words.len(), output.display());
+        }
 
-            if !failed_words.is_empty() {
-                unprocessed_words = Self::redact_words(failed_words)?;
-            } else {
-                break;
+        Commands::ListCached => {
+            let storage = db::open(&settings.storage)?;
+
+            let mut cached_books = storage.list_books().await?;
+            cached_books.sort_by(|a, b| a.book.cmp(&b.book));
+
+            for cached in cached_books {
+                let legacy = if cached.legacy { "\t(not migrated, title guessed from the file name)" } else { "" };
+                println!("{}\t{}\t{}{legacy}", cached.book.title, cached.word_count, format_size(cached.size_bytes));
             }
         }
 
-        Ok(processed_words)
-    }
+        Commands::RemoveCached { book_title, delete_deck } => {
+            let storage = db::open(&settings.storage)?;
+            let book = storage.find_book(book_title).await?
+                .ok_or(anyhow!("No cached words found for book '{book_title}'"))?;
 
-    pub async fn process_word(&self, word: &mut Word) -> Result<()> {
-        let word_stem = self.oxford_dict.word_stem(&word.text).await
-            .unwrap_or(word.text.to_owned());
+            if *delete_deck {
+                let renderer = CardRenderer::new(settings.rendering.clone())?;
+                let anki = AnkiConnectClient::new(settings.anki.clone(), renderer)?;
+                let deck_name = settings.anki.deck_name(&book);
 
-        let (translation, defined_word) = try_join!(
-            self.google_translate.translate(&word_stem),
-            self.oxford_dict.definitions(&word_stem))?;
+                anki.confirm_delete_deck(&deck_name, settings.processing.assume_yes).await?;
+                anki.delete_deck(&deck_name).await?;
+                info!("Deleted Anki deck '{deck_name}'");
+            }
 
-        word.text = defined_word.text;
-        word.translation = Some(translation);
-        word.definitions = defined_word.definitions;
+            storage.remove_book(&book).await?;
+            info!("Removed cached words of '{book}'");
+        }
 
-        Ok(())
-    }
+        Commands::RestoreToAnki { book_title, all } => {
+            settings.processing.offline = true;
 
-    fn select_book(books: Vec<Book>) -> Result<Book> {
-        Ok(Select::new("Select the book to import:", books)
-            .with_page_size(20)
-            .prompt()?)
-    }
+            let selection = match book_title {
+                Some(book_title) => BookSelection::Title(book_title.to_owned()),
+                None if *all => BookSelection::All,
+                None => BookSelection::Interactive,
+            };
 
-    fn redact_words(words: Vec<Word>) -> Result<Vec<Word>> {
-        let selected = MultiSelect::new("Select words to redact: ", words)
-            .prompt()?;
+            let word_processor = WordProcessor::new(&settings)?;
+            word_processor.process(false, selection, false).await?;
+        }
 
-        let mut new_words = Vec::new();
-        for mut word in selected {
-            let redacted_text = Text::new("Redact: ")
-                .with_initial_value(&word.text)
-                .prompt()?;
+        Commands::Init => init_config().await?,
 
-            word.text = redacted_text;
-            word.translation = None;
-            word.definitions = None;
+        Commands::Check => check_integrations(&settings).await?,
 
-            new_words.push(word);
-        }
+        Commands::ReprocessWord { book_title, word } => {
+            let word_processor = WordProcessor::new(&settings)?;
 
-        Ok(new_words)
+            word_processor.reprocess_words(BookSelection::Title(book_title.to_owned()), &[word.to_owned()], false).await?;
+        }
     }
 
-    async fn partition_by_processed<'a>(book: &Book, words: Vec<Word>) -> Result<(Vec<Word>, Vec<Word>)> {
-        let mut cached_words = db::get_words(book).await?
-            .into_iter()
-            .map(|word| (word.original_text.clone(), word))
-            .collect::<HashMap<String, Word>>();
+    Ok(())
+}
 
-        let (mut processed, mut unprocessed) = (Vec::new(), Vec::new());
+async fn init_config() -> Result<()> {
+    let templates = [
+        ("readwise", json!({ "token": "<Readwise access token>" })),
+        ("oxford_dict", json!({ "app_id": "<Oxford app id>", "app_key": "<Oxford app key>" })),
+        ("deepl", json!({ "auth_key": "<DeepL auth key>" })),
+        ("google_translate", json!({
+            "type": "service_account",
+            "project_id": "<project id>",
+            "private_key_id": "<private key id>",
+            "private_key": "<private key>",
+            "client_email": "<service account email>",
+            "client_id": "<client id>",
+            "auth_uri": "https://accounts.google.com/o/oauth2/auth",
+            "token_uri": "https://oauth2.googleapis.com/token",
+            "auth_provider_x509_cert_url": "https://www.googleapis.com/oauth2/v1/certs",
+            "client_x509_cert_url": "<client certificate url>"
+        })),
+        ("settings", json!({})),
+    ];
+
+    for (file_id, contents) in templates {
+        let path = util::config_file_path(file_id)?;
+
+        if util::write_config_template(file_id, &contents).await? {
+            println!("Created {}", path.display());
+        } else {
+            println!("Skipped {}, it already exists", path.display());
+        }
+    }
 
-        for word in words.into_iter() {
-            if let Some(cached_word) = cached_words.remove(&word.original_text) {
-                processed.push(cached_word);
-            } else {
-                unprocessed.push(word);
+    Ok(())
+}
+
+async fn check_integrations(settings: &Settings) -> Result<()> {
+    let readwise = async {
+        ReadwiseClient::new(settings.readwise.clone()).await?.check_auth().await
+    };
+    let oxford = async {
+        OxfordDictClient::new(settings.oxford.clone()).await?.check().await
+    };
+    let translation = async {
+        match settings.translation.provider {
+            TranslationProvider::Google => {
+                let renewal_margin = chrono::Duration::seconds(settings.translation.token_renewal_margin_secs);
+                TokenManager::new(&[google_translate::SCOPE], renewal_margin).await?.token().await.map(|_| ())
+            }
+            _ => {
+                // A cached translation wouldn't reach the provider
+                let translation = TranslationSettings { cache: false, ..settings.translation.clone() };
+                translator::create(&translation, &settings.oxford.source_language).await?.translate("hello").await.map(|_| ())
+            }
+        }
+    };
+    let anki = async {
+        let renderer = CardRenderer::new(settings.rendering.clone())?;
+        let anki = AnkiConnectClient::new(settings.anki.clone(), renderer)?;
+        anki.version().await?;
+        anki.validate_note_type().await
+    };
+
+    let (readwise, oxford, translation, anki) = tokio::join!(readwise, oxford, translation, anki);
+    let results = [("Readwise", readwise), ("Oxford", oxford), ("Translation", translation), ("AnkiConnect", anki)];
+
+    let mut failures = 0;
+    for (name, result) in results {
+        match result {
+            Ok(()) => println!("{name}: OK"),
+            Err(err) => {
+                failures += 1;
+                println!("{name}: FAILED ({err:#})");
             }
         }
+    }
 
-        Ok((unprocessed, processed))
+    if failures > 0 {
+        bail!("{failures} of 4 checks failed");
     }
+
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{bytes} B"),
+        1024..=1048575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1048576.0),
+    }
+}
+
+fn parse_date(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(date.and_hms_opt(0, 0, 0).unwrap(), Utc));
+    }
+
+    Ok(DateTime::parse_from_rfc3339(value)
+        .with_context(|| format!("'{value}' is neither YYYY-MM-DD nor an RFC 3339 date"))?
+        .with_timezone(&Utc))
+}
+
+async fn load_cached_book(storage: &dyn Storage, book_title: &str) -> Result<(Book, Vec<Word>)> {
+    let book = storage.find_book(book_title).await?
+        .ok_or(anyhow!("No cached words found for book '{book_title}'"))?;
+    let words = storage.get_words(&book).await?;
+
+    Ok((book, words))
 }
@@ -1,8 +1,8 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::fmt::{Display, Formatter, write};
+use std::fmt::{Display, Formatter};
 use serde::{Serialize, Deserialize};
-use strum::{Display, EnumString};
+use strum::{AsRefStr, EnumString};
 use std::string::ToString;
 use maud::Render;
 
@@ -10,7 +10,16 @@ use maud::Render;
 pub struct Book {
     pub id: u64,
     pub title: String,
-    pub author: Option<String>
+    pub author: Option<String>,
+    #[serde(default)]
+    pub source: BookSource,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BookSource {
+    #[default]
+    Readwise,
+    Reader(String),
 }
 
 impl Display for Book {
@@ -32,30 +41,30 @@ impl Eq for Book {}
 
 impl PartialOrd for Book {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Book {
+    fn cmp(&self, other: &Self) -> Ordering {
         if self.author.is_some() && other.author.is_some() {
             let author_cmp = self.author.cmp(&other.author);
 
             if author_cmp == Ordering::Equal {
-                Some(self.title.to_lowercase().cmp(&other.title.to_lowercase()))
+                self.title.to_lowercase().cmp(&other.title.to_lowercase())
             } else {
-                Some(author_cmp)
+                author_cmp
             }
         } else if self.author.is_some() {
-            Some(Ordering::Less)
+            Ordering::Less
         } else if other.author.is_some() {
-            Some(Ordering::Greater)
+            Ordering::Greater
         } else {
-            Some(self.title.to_lowercase().cmp(&other.title.to_lowercase()))
+            self.title.to_lowercase().cmp(&other.title.to_lowercase())
         }
     }
 }
 
-impl Ord for Book {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
-    }
-}
-
 pub type Definitions = HashMap<DefinitionCategory, Vec<Definition>>;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -63,7 +72,22 @@ pub struct Word {
     pub text: String,
     pub original_text: String,
     pub translation: Option<String>,
-    pub definitions: Option<Definitions>
+    pub definitions: Option<Definitions>,
+    pub word_family: Option<Vec<String>>,
+    pub synonyms: Option<Vec<String>>,
+    pub antonyms: Option<Vec<String>>,
+    pub phonetic: Option<String>,
+    pub audio_url: Option<String>,
+    pub audio_file: Option<String>,
+    pub provenance: Option<Provenance>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inflections: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub see_also: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub etymologies: Vec<String>,
 }
 
 impl Word {
@@ -72,17 +96,61 @@ impl Word {
             text: text.to_owned(),
             original_text: text.to_owned(),
             translation: None,
-            definitions: None
+            definitions: None,
+            word_family: None,
+            synonyms: None,
+            antonyms: None,
+            phonetic: None,
+            audio_url: None,
+            audio_file: None,
+            provenance: None,
+            inflections: Vec::new(),
+            see_also: Vec::new(),
+            context: None,
+            etymologies: Vec::new(),
         }
     }
 }
 
+impl Word {
+    pub fn add_inflection(&mut self, inflection: String) {
+        if inflection != self.text && inflection != self.original_text && !self.inflections.contains(&inflection) {
+            self.inflections.push(inflection);
+        }
+    }
+
+    pub fn is_phrase(&self) -> bool {
+        self.text.trim().contains(char::is_whitespace)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.translation.is_some()
+            && self.definitions.as_ref().is_some_and(|definitions| !definitions.is_empty())
+    }
+}
+
 impl Display for Word {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.text)
     }
 }
 
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Provenance {
+    pub dictionary: Option<String>,
+    pub dialect: Option<String>,
+    pub followed_reference: Option<String>,
+    pub translation: Option<TranslationOrigin>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TranslationOrigin {
+    Fresh,
+    Preset,
+    Cached,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DefinitionsEntry {
     pub definitions: Vec<Definition>,
@@ -92,12 +160,16 @@ pub struct DefinitionsEntry {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Definition {
     pub definition: Option<String>,
-    pub examples: Vec<String>
+    pub examples: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grammatical_features: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub registers: Vec<String>,
 }
 
-#[derive(Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-#[derive(EnumString, Display)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+#[derive(EnumString, AsRefStr)]
 #[strum(serialize_all = "snake_case")]
 pub enum DefinitionCategory {
     Noun,
@@ -108,11 +180,62 @@ pub enum DefinitionCategory {
     Interjection,
     Idiomatic,
     Pronoun,
-    Residual
+    Conjunction,
+    Determiner,
+    Numeral,
+    Particle,
+    Exclamation,
+    Contraction,
+    Prefix,
+    Suffix,
+    CombiningForm,
+    Residual,
+    #[strum(default)]
+    Other(String),
+}
+
+impl DefinitionCategory {
+    pub fn is_standard(&self) -> bool {
+        !matches!(self,
+            DefinitionCategory::Idiomatic
+            | DefinitionCategory::Residual
+            | DefinitionCategory::Contraction
+            | DefinitionCategory::Prefix
+            | DefinitionCategory::Suffix
+            | DefinitionCategory::CombiningForm
+            | DefinitionCategory::Other(_))
+    }
+}
+
+impl Display for DefinitionCategory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DefinitionCategory::Other(category) => write!(f, "{category}"),
+            category => write!(f, "{}", category.as_ref()),
+        }
+    }
+}
+
+impl From<String> for DefinitionCategory {
+    fn from(category: String) -> Self {
+        match category.parse() {
+            Ok(category) => category,
+            Err(_) => DefinitionCategory::Other(category),
+        }
+    }
+}
+
+impl From<DefinitionCategory> for String {
+    fn from(category: DefinitionCategory) -> Self {
+        category.to_string()
+    }
 }
 
 impl Render for DefinitionCategory {
     fn render_to(&self, buffer: &mut String) {
-        buffer.push_str(&self.to_string())
+        match self {
+            DefinitionCategory::Other(category) => buffer.push_str(category),
+            category => buffer.push_str(category.as_ref()),
+        }
     }
 }
@@ -1,5 +1,6 @@
-use std::{thread, time};
+use std::time;
 use std::collections::HashMap;
+use std::io::ErrorKind;
 use std::str::FromStr;
 
 use futures::future::{BoxFuture, FutureExt};
@@ -7,17 +8,67 @@ use futures::future::{BoxFuture, FutureExt};
 use anyhow::{anyhow, bail, Context, Result};
 use itertools::Either::{Left, Right};
 use itertools::Itertools;
-use log::{info, warn};
-use reqwest::{header, StatusCode};
+use log::{debug, info, warn};
+use reqwest::{header, Response, StatusCode};
 use reqwest::header::HeaderValue;
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
-
-use crate::model::{Definition, DefinitionCategory, DefinitionsEntry, Word};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::db;
+use crate::dictionary::{Dictionary, DictionaryError};
+use crate::model::{Definition, DefinitionCategory, DefinitionsEntry, Provenance, Word};
+use crate::settings::{DefinitionStrategy, OxfordSettings, Service};
 use crate::util;
 
 pub struct OxfordDictClient {
     http: reqwest::Client,
+    max_retries: u32,
+    backoff_base: time::Duration,
+    word_family: bool,
+    thesaurus: bool,
+    standard_categories_only: bool,
+    rate_limiter: Option<RateLimiter>,
+    lemma_cache: Option<Mutex<LemmaCache>>,
+    definition_strategy: DefinitionStrategy,
+    source_language: String,
+    languages: Vec<String>,
+}
+
+// Lemmas keyed by source language and then by word, saved in batches of `CACHE_SAVE_BATCH` new lemmas
+// and once more by `flush`
+#[derive(Default)]
+struct LemmaCache {
+    lemmas: HashMap<String, HashMap<String, String>>,
+    unsaved: usize,
+}
+
+// Spaces requests evenly so concurrent lookups don't burst past the per-minute cap
+struct RateLimiter {
+    interval: time::Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn per_minute(requests: u32) -> RateLimiter {
+        RateLimiter {
+            interval: time::Duration::from_secs(60) / requests.max(1),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
 }
 
 #[derive(Debug)]
@@ -46,7 +97,23 @@ enum MappingResult<T> {
     OtherSources(Vec<String>),
 }
 
+struct EntriesLookup {
+    word_id: String,
+    definitions: Vec<DefinitionsEntry>,
+    derivatives: Vec<String>,
+    derivative_of: Vec<String>,
+    phonetic_spellings: Vec<String>,
+    audio_url: Option<String>,
+    see_also: Vec<String>,
+    etymologies: Vec<String>,
+}
+
 const URL: &str = "https://od-api.oxforddictionaries.com/api/v2";
+const DICTIONARY_NAME: &str = "Oxford";
+// The thesaurus API only covers English
+const THESAURUS_LANGUAGE: &str = "en";
+const LEMMA_CACHE_FILENAME: &str = "lemmas.json";
+const CACHE_SAVE_BATCH: usize = 50;
 
 #[derive(Deserialize, Serialize)]
 struct LemmasResponse {
@@ -89,12 +156,31 @@ struct EntriesLexicalEntry {
     lexical_category: CommonTextEntry,
     #[serde(rename = "derivativeOf")]
     derivative_of: Option<Vec<CommonTextEntry>>,
+    derivatives: Option<Vec<CommonTextEntry>>,
+    pronunciations: Option<Vec<EntriesPronunciation>>,
+    #[serde(rename = "grammaticalFeatures")]
+    grammatical_features: Option<Vec<CommonTextEntry>>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[derive(Debug)]
 struct EntriesEntry {
     senses: Vec<EntriesSense>,
+    etymologies: Option<Vec<String>>,
+    pronunciations: Option<Vec<EntriesPronunciation>>,
+    #[serde(rename = "grammaticalFeatures")]
+    grammatical_features: Option<Vec<CommonTextEntry>>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[derive(Debug)]
+struct EntriesPronunciation {
+    #[serde(rename = "phoneticNotation")]
+    phonetic_notation: Option<String>,
+    #[serde(rename = "phoneticSpelling")]
+    phonetic_spelling: Option<String>,
+    #[serde(rename = "audioFile")]
+    audio_file: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -107,14 +193,51 @@ struct EntriesSense {
     subsenses: Option<Vec<EntriesSense>>,
     #[serde(rename = "crossReferences")]
     cross_references: Option<Vec<CommonTextEntry>>,
+    #[serde(rename = "grammaticalFeatures")]
+    grammatical_features: Option<Vec<CommonTextEntry>>,
+    registers: Option<Vec<CommonTextEntry>>,
 }
 
 #[derive(Deserialize, Serialize)]
 #[derive(Debug)]
 struct CommonTextEntry {
+    id: Option<String>,
     text: String,
 }
 
+#[derive(Deserialize)]
+struct ThesaurusResponse {
+    results: Option<Vec<ThesaurusResults>>,
+}
+
+#[derive(Deserialize)]
+struct ThesaurusResults {
+    #[serde(rename = "lexicalEntries")]
+    lexical_entries: Vec<ThesaurusLexicalEntry>,
+}
+
+#[derive(Deserialize)]
+struct ThesaurusLexicalEntry {
+    entries: Vec<ThesaurusEntry>,
+}
+
+#[derive(Deserialize)]
+struct ThesaurusEntry {
+    senses: Option<Vec<ThesaurusSense>>,
+}
+
+#[derive(Deserialize)]
+struct ThesaurusSense {
+    synonyms: Option<Vec<CommonTextEntry>>,
+    antonyms: Option<Vec<CommonTextEntry>>,
+}
+
+#[derive(Default)]
+pub struct Thesaurus {
+    pub synonyms: Vec<String>,
+    pub antonyms: Vec<String>,
+}
+
 #[derive(Deserialize, Serialize)]
 #[derive(Debug)]
 struct Credentials {
@@ -123,7 +246,7 @@ struct Credentials {
 }
 
 impl OxfordDictClient {
-    pub async fn new() -> Result<OxfordDictClient> {
+    pub async fn new(settings: OxfordSettings) -> Result<OxfordDictClient> {
         let creds = Self::load_creds().await?;
 
         let mut default_headers = header::HeaderMap::new();
@@ -132,112 +255,373 @@ impl OxfordDictClient {
         default_headers.insert("App-Id", HeaderValue::from_str(&creds.app_id)?);
         default_headers.insert("App-Key", HeaderValue::from_str(&creds.app_key)?);
 
-        let http = reqwest::Client::builder()
-            .default_headers(default_headers)
-            .connection_verbose(true)
-            .build()?;
+        let http = util::http_client(default_headers)?;
+
+        let thesaurus = settings.thesaurus && settings.source_language == THESAURUS_LANGUAGE;
+        if settings.thesaurus && !thesaurus {
+            warn!("Oxford thesaurus isn't available for '{}', skipping synonyms and antonyms", settings.source_language);
+        }
+
+        Ok(OxfordDictClient {
+            http,
+            max_retries: settings.max_retries,
+            backoff_base: time::Duration::from_millis(settings.backoff_base_ms),
+            word_family: settings.word_family,
+            thesaurus,
+            standard_categories_only: settings.standard_categories_only,
+            rate_limiter: settings.requests_per_minute.map(RateLimiter::per_minute),
+            definition_strategy: settings.definition_strategy,
+            languages: settings.entry_languages(),
+            source_language: settings.source_language,
+            lemma_cache: if settings.lemma_cache { Some(Mutex::new(Self::load_lemma_cache().await?)) } else { None },
+        })
+    }
+
+    async fn load_lemma_cache() -> Result<LemmaCache> {
+        let filename = format!("{}/{LEMMA_CACHE_FILENAME}", db::data_dir());
 
-        Ok(OxfordDictClient { http })
+        let lemmas = match fs::read_to_string(&filename).await {
+            Ok(json) => serde_json::from_str(&json)
+                .with_context(|| format!("Couldn't deserialize lemmas from file at '{filename}'"))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err).with_context(|| format!("Couldn't read lemmas from '{filename}'")),
+        };
+
+        Ok(LemmaCache { lemmas, unsaved: 0 })
+    }
+
+    /// Saves the lemmas looked up since the last save
+    pub async fn flush(&self) {
+        if let Some(cache) = &self.lemma_cache {
+            let mut cache = cache.lock().await;
+            if cache.unsaved > 0 {
+                Self::save_lemma_cache(&mut cache).await;
+            }
+        }
+    }
+
+    // A failed save only costs lookups on the next run, so it doesn't fail the word
+    async fn save_lemma_cache(cache: &mut LemmaCache) {
+        match Self::write_lemma_cache(&cache.lemmas).await {
+            Ok(()) => cache.unsaved = 0,
+            Err(err) => warn!("Failed to save the lemma cache: {err:#}"),
+        }
+    }
+
+    async fn write_lemma_cache(lemmas: &HashMap<String, HashMap<String, String>>) -> Result<()> {
+        let filename = format!("{}/{LEMMA_CACHE_FILENAME}", db::data_dir());
+        let tmp_filename = format!("{filename}.tmp");
+
+        fs::create_dir_all(db::data_dir()).await
+            .with_context(|| format!("Failed to create dir '{}'", db::data_dir()))?;
+
+        let json = serde_json::to_string(lemmas)
+            .context("Failed to serialize lemmas")?;
+
+        fs::write(&tmp_filename, json).await
+            .with_context(|| format!("Failed to write file {tmp_filename}"))?;
+        fs::rename(&tmp_filename, &filename).await
+            .with_context(|| format!("Failed to move {tmp_filename} to {filename}"))?;
+
+        Ok(())
     }
 
     async fn load_creds() -> Result<Credentials> {
-        util::load_json_config("oxford_dict").await
-            .with_context(|| format!("Failed to get credentials for oxford dict client"))
+        util::load_credentials("oxford_dict", &[("app_id", "OXFORD_APP_ID"), ("app_key", "OXFORD_APP_KEY")]).await
+            .context("Failed to get credentials for oxford dict client")
+    }
+
+    /// Checks the credentials with a lemma lookup that bypasses the lemma cache
+    pub async fn check(&self) -> Result<()> {
+        self.lemmas("words").await.map(|_| ())
     }
 
     pub async fn word_stem(&self, word: &str) -> Result<String> {
-        self.lemmas(word).await
+        // Lemmas are only available for single words
+        if word.trim().contains(char::is_whitespace) {
+            return Ok(word.trim().to_owned());
+        }
+
+        if let Some(cache) = &self.lemma_cache {
+            let cached = cache.lock().await
+                .lemmas
+                .get(&self.source_language)
+                .and_then(|lemmas| lemmas.get(word))
+                .cloned();
+
+            if let Some(lemma) = cached {
+                debug!("Using cached lemma for '{word}'");
+                return Ok(lemma);
+            }
+        }
+
+        let lemma = match self.lemmas(word).await {
+            Err(err) if DictionaryError::is_not_found(&err) => {
+                info!("No lemmas found for '{word}', using it as is");
+                return Ok(word.to_owned());
+            }
+            result => result?,
+        };
+
+        if let Some(cache) = &self.lemma_cache {
+            let mut cache = cache.lock().await;
+            cache.lemmas.entry(self.source_language.to_owned())
+                .or_default()
+                .insert(word.to_owned(), lemma.to_owned());
+
+            cache.unsaved += 1;
+            if cache.unsaved >= CACHE_SAVE_BATCH {
+                Self::save_lemma_cache(&mut cache).await;
+            }
+        }
+
+        Ok(lemma)
     }
 
     pub async fn definitions(&self, word_stem: &str) -> Result<Word> {
-        let en_us_entries = self.entries(word_stem, "en-us").await;
+        let mut errors = Vec::new();
 
-        if en_us_entries.is_ok() {
-            return en_us_entries.map(|e| self.process_entries(e));
+        for lang in &self.languages {
+            match self.entries(word_stem, lang).await {
+                Ok(entries) => return Ok(self.build_word(word_stem, entries, lang).await),
+                Err(err) if DictionaryError::is_credentials_rejected(&err) => return Err(err),
+                Err(err) => errors.push(err),
+            }
         }
 
-        let en_gb_entries = self.entries(word_stem, "en-gb").await;
-        if en_gb_entries.is_ok() {
-            return en_gb_entries.map(|e| self.process_entries(e));
+        if errors.is_empty() {
+            bail!("No Oxford languages configured");
         }
 
-        let errors = vec![en_us_entries.err().unwrap(), en_gb_entries.err().unwrap()];
+        if errors.iter().all(DictionaryError::is_not_found) {
+            return Err(DictionaryError::NotFound { dictionary: DICTIONARY_NAME, word: word_stem.to_owned() })?;
+        }
 
-        return Err(OxfordClientError::CompositeError(errors))?;
+        Err(OxfordClientError::CompositeError(errors))?
     }
 
-    fn process_entries(&self, entries: (String, Vec<DefinitionsEntry>)) -> Word {
+    async fn build_word(&self, word_stem: &str, entries: EntriesLookup, lang: &str) -> Word {
+        let word_family = if self.word_family {
+            Some(self.word_family(&entries, lang).await)
+        } else {
+            None
+        };
+
+        let thesaurus = if self.thesaurus {
+            match self.thesaurus(&entries.word_id).await {
+                Ok(thesaurus) => thesaurus,
+                Err(err) => {
+                    warn!("Failed to get thesaurus entry of '{}': {err}", entries.word_id);
+                    Thesaurus::default()
+                }
+            }
+        } else {
+            Thesaurus::default()
+        };
+
+        let mut word = self.process_entries(entries);
+        word.word_family = word_family.filter(|family| !family.is_empty());
+        word.synonyms = Some(thesaurus.synonyms).filter(|synonyms| !synonyms.is_empty());
+        word.antonyms = Some(thesaurus.antonyms).filter(|antonyms| !antonyms.is_empty());
+        word.provenance = Some(Provenance {
+            dialect: Some(lang.to_owned()),
+            followed_reference: Some(word.text.to_owned()).filter(|text| text != word_stem),
+            ..Provenance::default()
+        });
+        word
+    }
+
+    pub async fn thesaurus(&self, word_id: &str) -> Result<Thesaurus> {
+        let response: ThesaurusResponse = self.make_request(&format!("/thesaurus/{THESAURUS_LANGUAGE}/{}", word_id.replace(' ', "_"))).await?;
+
+        let senses = response.results.into_iter().flatten()
+            .flat_map(|result| result.lexical_entries)
+            .flat_map(|lexical_entry| lexical_entry.entries)
+            .flat_map(|entry| entry.senses.into_iter().flatten())
+            .collect_vec();
+
+        let texts = |field: fn(&ThesaurusSense) -> &Option<Vec<CommonTextEntry>>| -> Vec<String> {
+            senses.iter()
+                .flat_map(|sense| field(sense).iter().flatten())
+                .map(|entry| entry.text.to_owned())
+                .unique()
+                .collect()
+        };
+
+        Ok(Thesaurus {
+            synonyms: texts(|sense| &sense.synonyms),
+            antonyms: texts(|sense| &sense.antonyms),
+        })
+    }
+
+    async fn word_family(&self, entries: &EntriesLookup, lang: &str) -> Vec<String> {
+        let mut family = entries.derivatives.clone();
+
+        for root in &entries.derivative_of {
+            family.push(root.to_owned());
+
+            match self.entries(root, lang).await {
+                Ok(root_entries) => family.extend(root_entries.derivatives),
+                Err(err) => warn!("Failed to get word family of '{root}': {err}"),
+            }
+        }
+
+        family.into_iter()
+            .filter(|member| member != &entries.word_id)
+            .unique()
+            .collect()
+    }
+
+    fn process_entries(&self, entries: EntriesLookup) -> Word {
         let mut definitions = HashMap::new();
 
-        entries.1.into_iter()
+        entries.definitions.into_iter()
+            .filter(|def_entry| !self.standard_categories_only || def_entry.category.is_standard())
             .map(|def_entry| (def_entry.category, def_entry.definitions))
             .for_each(|(key, ref mut val)| {
                 definitions.entry(key).or_insert_with(Vec::new).append(val);
             });
 
+        let phonetic = Some(entries.phonetic_spellings.join(", "))
+            .filter(|phonetic| !phonetic.is_empty());
+
         Word {
-            text: entries.0.to_owned(),
-            original_text: entries.0,
-            translation: None,
             definitions: Some(definitions),
+            phonetic,
+            audio_url: entries.audio_url,
+            see_also: entries.see_also,
+            etymologies: entries.etymologies,
+            ..Word::from_text(&entries.word_id)
         }
     }
 
-    fn entries<'a>(&'a self, word_id: &'a str, lang: &'a str) -> BoxFuture<Result<(String, Vec<DefinitionsEntry>)>> {
+    fn entries<'a>(&'a self, word_id: &'a str, lang: &'a str) -> BoxFuture<'a, Result<EntriesLookup>> {
         async move {
-            let response: EntriesResponse = self.make_request(&format!("/entries/{lang}/{word_id}")).await?;
+            let response: EntriesResponse = self.make_request(&format!("/entries/{lang}/{}", word_id.replace(' ', "_"))).await?;
 
             if response.results.is_none() {
                 bail!("Entries results array is empty");
             }
 
-            let (successes, failures): (Vec<_>, Vec<_>) = response.results.unwrap().into_iter()
+            let lexical_entries: Vec<EntriesLexicalEntry> = response.results.unwrap().into_iter()
                 .flat_map(|result| result.lexical_entries)
-                .map(|lexical_entry| OxfordDictClient::map_lexical_entry(word_id, lexical_entry))
-                .partition_result();
+                .collect();
+
+            let related_texts = |field: fn(&EntriesLexicalEntry) -> &Option<Vec<CommonTextEntry>>| -> Vec<String> {
+                lexical_entries.iter()
+                    .flat_map(|lexical_entry| field(lexical_entry).iter().flatten())
+                    .map(|entry| entry.text.to_owned())
+                    .unique()
+                    .collect()
+            };
+            let derivatives = related_texts(|lexical_entry| &lexical_entry.derivatives);
+            let derivative_of = related_texts(|lexical_entry| &lexical_entry.derivative_of);
+            let pronunciations = Self::pronunciations(&lexical_entries);
+            let phonetic_spellings = pronunciations.iter()
+                .filter(|pronunciation| pronunciation.phonetic_notation.as_deref() == Some("IPA"))
+                .filter_map(|pronunciation| pronunciation.phonetic_spelling.to_owned())
+                .unique()
+                .collect();
+            let audio_url = pronunciations.iter()
+                .find_map(|pronunciation| pronunciation.audio_file.to_owned());
+            let etymologies = lexical_entries.iter()
+                .flat_map(|lexical_entry| &lexical_entry.entries)
+                .flat_map(|entry| entry.etymologies.iter().flatten())
+                .cloned()
+                .unique()
+                .collect();
 
-            if !failures.is_empty() {
-                return Err(OxfordClientError::CompositeError(failures))?;
-            }
+            let (successes, failures): (Vec<_>, Vec<_>) = lexical_entries.into_iter()
+                .map(|lexical_entry| self.map_lexical_entry(word_id, lexical_entry))
+                .partition_result();
 
             let (results, other_sources): (Vec<_>, Vec<_>) = successes.into_iter()
                 .partition_map(|mapping_result| match mapping_result {
                     MappingResult::Result(r) => Left(r),
                     MappingResult::OtherSources(os) => Right(os)
                 });
-            let other_sources: Vec<String> = other_sources.into_iter().flatten().collect();
+            let other_sources = other_sources.into_iter()
+                .flatten()
+                .filter(|source| source != word_id)
+                .unique()
+                .collect_vec();
+
+            // A category that failed to map only loses its own definitions, unless nothing else is usable
+            if !failures.is_empty() {
+                if results.is_empty() && other_sources.is_empty() {
+                    return Err(OxfordClientError::CompositeError(failures))?;
+                }
 
-            return if !results.is_empty() {
-                if !other_sources.is_empty() {
-                    warn!("other sources are not empty for '{word_id}': {:?}", other_sources)
+                for failure in &failures {
+                    warn!("Skipping a lexical entry of '{word_id}': {failure:#}");
                 }
-                Ok((word_id.to_owned(), results))
-            } else if !other_sources.is_empty() {
-                //TODO: handle multiple other sources?
-                let source = other_sources.first().unwrap();
+            }
+
+            if !results.is_empty() {
+                Ok(EntriesLookup {
+                    word_id: word_id.to_owned(),
+                    definitions: results,
+                    derivatives,
+                    derivative_of,
+                    phonetic_spellings,
+                    audio_url,
+                    see_also: other_sources,
+                    etymologies,
+                })
+            } else if let Some((source, rest)) = other_sources.split_first() {
+                // Only the first source is followed for the definitions, the rest are kept as pointers
                 info!("Failed to get definition for '{word_id}', getting it from other source: '{source}'");
-                self.entries(source, lang).await
+                let mut lookup = self.entries(source, lang).await?;
+                lookup.see_also = rest.iter().cloned()
+                    .chain(lookup.see_also)
+                    .filter(|see_also| see_also != source)
+                    .unique()
+                    .collect();
+
+                Ok(lookup)
             } else {
                 Err(anyhow!("Definition entries and other sources are empty for '{word_id}'"))
-            };
+            }
         }.boxed()
     }
 
-    fn map_lexical_entry(word_id: &str, lexical_entry: EntriesLexicalEntry) -> Result<MappingResult<DefinitionsEntry>> {
-        let lexical_category = lexical_entry.lexical_category.text.trim().to_lowercase();
+    fn pronunciations(lexical_entries: &[EntriesLexicalEntry]) -> Vec<&EntriesPronunciation> {
+        lexical_entries.iter()
+            .flat_map(|lexical_entry| {
+                let entries_pronunciations = lexical_entry.entries.iter()
+                    .flat_map(|entry| entry.pronunciations.iter().flatten());
+
+                lexical_entry.pronunciations.iter().flatten().chain(entries_pronunciations)
+            })
+            .collect()
+    }
+
+    fn map_lexical_entry(&self, word_id: &str, lexical_entry: EntriesLexicalEntry) -> Result<MappingResult<DefinitionsEntry>> {
+        // The id is English for every source language, while the text is localized
+        let lexical_category = lexical_entry.lexical_category.id.as_deref()
+            .unwrap_or(&lexical_entry.lexical_category.text)
+            .trim().to_lowercase().replace(' ', "_");
         let category = DefinitionCategory::from_str(&lexical_category)
             .with_context(|| format!("Failed to convert lexical category from '{lexical_category}'"))?;
+        if let DefinitionCategory::Other(_) = category {
+            warn!("Unknown lexical category '{lexical_category}' for '{word_id}'");
+        }
+
+        let lexical_features = Self::feature_texts(&[], &lexical_entry.grammatical_features);
 
         let (definitions, other_sources): (Vec<_>, Vec<_>) = lexical_entry.entries.into_iter()
-            .flat_map(|entry| entry.senses)
-            .flat_map(|sense| OxfordDictClient::build_definitions(sense))
+            .flat_map(|entry| {
+                let entry_features = Self::feature_texts(&lexical_features, &entry.grammatical_features);
+                entry.senses.into_iter().map(move |sense| (entry_features.clone(), sense))
+            })
+            .flat_map(|(features, sense)| self.build_definitions(sense, &features))
             .partition_map(|mapping_result| match mapping_result {
                 MappingResult::Result(r) => Left(r),
                 MappingResult::OtherSources(os) => Right(os)
             });
 
         let definitions: Vec<Definition> = definitions.into_iter()
-            .filter(|def| !def.definition.is_none())
+            .filter(|def| def.definition.is_some())
             .collect();
         let mut other_sources: Vec<String> = other_sources.into_iter().flatten().collect();
 
@@ -245,7 +629,7 @@ impl OxfordDictClient {
             .map(|derivative_of| derivative_of.into_iter().map(|dof| dof.text).collect())
             .unwrap_or_default();
 
-        return if !definitions.is_empty() {
+        if !definitions.is_empty() {
             if !other_sources.is_empty() {
                 warn!("other sources are not empty for {word_id}: {:?}", other_sources);
             }
@@ -256,46 +640,90 @@ impl OxfordDictClient {
             Ok(MappingResult::OtherSources(other_sources))
         } else {
             Err(anyhow!("Failed to find definitions or other sources for word '{word_id}' and category '{category}'"))
-        };
+        }
     }
 
-    fn build_definitions(mut sense: EntriesSense) -> Vec<MappingResult<Definition>> {
-        let mut sub_senses_definitions = sense.subsenses.take().unwrap_or_default()
-            .into_iter()
-            .map(|ss| OxfordDictClient::build_definition(ss))
-            .collect::<Vec<_>>();
+    // Grammatical features and registers apply to everything nested under the entry or sense that declares them
+    fn feature_texts(inherited: &[String], features: &Option<Vec<CommonTextEntry>>) -> Vec<String> {
+        inherited.iter().cloned()
+            .chain(features.iter().flatten().map(|feature| feature.text.to_lowercase()))
+            .unique()
+            .collect()
+    }
 
-        let main_sense_definition = OxfordDictClient::build_definition(sense);
+    fn build_definitions(&self, mut sense: EntriesSense, features: &[String]) -> Vec<MappingResult<Definition>> {
+        let sub_senses = sense.subsenses.take().unwrap_or_default();
+        let sense_features = Self::feature_texts(features, &sense.grammatical_features);
+        let sense_registers = Self::feature_texts(&[], &sense.registers);
 
-        sub_senses_definitions.insert(0, main_sense_definition);
-        sub_senses_definitions
+        let mut definitions = self.build_definition(sense, &sense_features, &sense_registers);
+        for sub_sense in sub_senses {
+            let sub_sense_features = Self::feature_texts(&sense_features, &sub_sense.grammatical_features);
+            let sub_sense_registers = Self::feature_texts(&sense_registers, &sub_sense.registers);
+            definitions.extend(self.build_definition(sub_sense, &sub_sense_features, &sub_sense_registers));
+        }
+
+        definitions
     }
 
-    fn build_definition(sense: EntriesSense) -> MappingResult<Definition> {
+    fn build_definition(&self, sense: EntriesSense, features: &[String], registers: &[String]) -> Vec<MappingResult<Definition>> {
         let short_definitions = sense.short_definitions.unwrap_or_default();
         let definitions = sense.definitions.unwrap_or_default();
+        let candidates = || short_definitions.iter().chain(&definitions);
+
+        let texts: Vec<String> = match self.definition_strategy {
+            DefinitionStrategy::First => short_definitions.first().or(definitions.first()).cloned().into_iter().collect(),
+            DefinitionStrategy::Shortest => candidates().min_by_key(|text| text.len()).cloned().into_iter().collect(),
+            DefinitionStrategy::Longest => candidates().max_by_key(|text| text.len()).cloned().into_iter().collect(),
+            // Short definitions abbreviate the full ones, so they are only used when there are no full ones
+            DefinitionStrategy::All if definitions.is_empty() => short_definitions.clone(),
+            DefinitionStrategy::All => definitions.clone(),
+        };
 
-        let definition = short_definitions.first()
-            .or(definitions.first())
-            .cloned();
-
-        let examples = sense.examples.unwrap_or_default()
+        let examples: Vec<String> = sense.examples.unwrap_or_default()
             .iter()
             .map(|example| example.text.clone())
             .collect();
 
         let cross_references = sense.cross_references.unwrap_or_default();
 
-        return if definition.is_none() && !cross_references.is_empty() {
+        if texts.is_empty() && !cross_references.is_empty() {
             let cross_references = cross_references.iter().map(|cte| cte.text.to_lowercase()).collect();
-            MappingResult::OtherSources(cross_references)
+            vec![MappingResult::OtherSources(cross_references)]
+        } else if texts.is_empty() {
+            vec![MappingResult::Result(Definition {
+                definition: None,
+                examples,
+                grammatical_features: features.to_vec(),
+                registers: registers.to_vec(),
+            })]
         } else {
-            MappingResult::Result(Definition { definition, examples })
-        };
+            // Examples illustrate the sense, so they are kept with its first definition only
+            texts.into_iter().enumerate()
+                .map(|(index, text)| MappingResult::Result(Definition {
+                    definition: Some(text),
+                    examples: if index == 0 { examples.clone() } else { Vec::new() },
+                    grammatical_features: features.to_vec(),
+                    registers: registers.to_vec(),
+                }))
+                .collect()
+        }
+    }
+
+    pub async fn download_audio(&self, url: &str) -> Result<Vec<u8>> {
+        info!("Downloading {url}");
+        util::count_request(Service::Oxford);
+        let response = self.http.get(url).send().await?;
+
+        if !response.status().is_success() {
+            bail!("Failed to download audio from '{url}': status {}", response.status());
+        }
+
+        Ok(response.bytes().await?.to_vec())
     }
 
     async fn lemmas(&self, word: &str) -> Result<String> {
-        let response: LemmasResponse = self.make_request(&format!("/lemmas/en/{word}")).await?;
+        let response: LemmasResponse = self.make_request(&format!("/lemmas/{}/{word}", self.source_language)).await?;
 
         if response.results.is_none() {
             bail!("Lemmas results array is empty, bailing early")
@@ -312,7 +740,7 @@ impl OxfordDictClient {
         if inflections.len() > 1 {
             inflections.iter()
                 .find(|inflection| inflection.as_str() == word)
-                .or(inflections.iter().next())
+                .or(inflections.first())
                 .cloned()
                 .ok_or(anyhow!("No inflections found for {word}"))
         } else {
@@ -323,24 +751,86 @@ impl OxfordDictClient {
     }
 
     async fn make_request<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
-        for _ in 1..3 {
-            let url = format!("{URL}{path}");
+        let url = format!("{URL}{path}");
+        let mut last_failure = String::new();
+
+        for attempt in 0..=self.max_retries {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire().await;
+            }
+
             info!("Requesting {url}");
-            let response = self.http.get(&url).send().await?;
+            util::count_request(Service::Oxford);
 
-            if response.status() != StatusCode::TOO_MANY_REQUESTS {
-                let result = response.json::<T>().await?;
-                return Ok(result);
-            } else {
-                let retry_after: u64 = response
-                    .headers()
-                    .get("Retry-After").ok_or_else(|| anyhow!("Failed to get Retry-After header"))?
-                    .to_str()?
-                    .parse::<u64>()?;
-                info!("Waiting {} seconds...", retry_after);
-                tokio::time::sleep(time::Duration::from_secs(retry_after)).await;
+            let delay = match self.http.get(&url).send().await {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    last_failure = format!("status {}", response.status());
+                    Self::retry_after(&response).unwrap_or_else(|| self.backoff(attempt))
+                }
+                Ok(response) if response.status() == StatusCode::UNAUTHORIZED
+                    || response.status() == StatusCode::FORBIDDEN => {
+                    let detail = format!("{}, check app_id/app_key", response.status());
+                    return Err(DictionaryError::CredentialsRejected { dictionary: DICTIONARY_NAME, detail })?;
+                }
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                    return Err(DictionaryError::NotFound { dictionary: DICTIONARY_NAME, word: Self::requested_word(path) })?;
+                }
+                Ok(response) if response.status().is_server_error() => {
+                    last_failure = format!("status {}", response.status());
+                    self.backoff(attempt)
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    return response.json::<T>().await
+                        .with_context(|| format!("Unexpected response from Oxford dict for '{url}' (status {status})"));
+                }
+                Err(err) => {
+                    last_failure = util::describe_request_error(&err);
+                    self.backoff(attempt)
+                }
+            };
+
+            if attempt < self.max_retries {
+                info!("Request to {url} failed with {last_failure}, waiting {} ms...", delay.as_millis());
+                tokio::time::sleep(delay).await;
             }
         }
-        bail!("Failed to get response from Oxford dict in time");
+
+        bail!("Failed to get response from Oxford dict for '{url}' after {} attempts, last failure: {last_failure}",
+            self.max_retries + 1);
+    }
+
+    fn requested_word(path: &str) -> String {
+        path.rsplit('/').next().unwrap_or(path).replace('_', " ")
+    }
+
+    fn retry_after(response: &Response) -> Option<time::Duration> {
+        response.headers()
+            .get("Retry-After")?
+            .to_str().ok()?
+            .parse::<u64>().ok()
+            .map(time::Duration::from_secs)
+    }
+
+    fn backoff(&self, attempt: u32) -> time::Duration {
+        self.backoff_base.saturating_mul(2u32.saturating_pow(attempt))
+    }
+}
+
+impl Dictionary for OxfordDictClient {
+    fn name(&self) -> &str {
+        "oxford"
+    }
+
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        OxfordDictClient::flush(self).boxed()
+    }
+
+    fn word_stem<'a>(&'a self, word: &'a str) -> BoxFuture<'a, Result<String>> {
+        OxfordDictClient::word_stem(self, word).boxed()
+    }
+
+    fn definitions<'a>(&'a self, word_stem: &'a str) -> BoxFuture<'a, Result<Word>> {
+        OxfordDictClient::definitions(self, word_stem).boxed()
     }
 }
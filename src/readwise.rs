@@ -1,18 +1,30 @@
-use std::collections::HashMap;
-use std::{thread, time};
+use std::collections::{HashMap, HashSet};
+use std::time;
 
-use anyhow::{anyhow, Context, Result};
-use itertools::Itertools;
-use log::info;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use regex::Regex;
+use log::{info, warn};
 use reqwest::header::HeaderValue;
 use reqwest::{header, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
 use crate::{model, util};
-use crate::model::Word;
+use crate::model::{BookSource, Word};
+use crate::settings::{ReadwiseSettings, Service};
 
 pub struct ReadwiseClient {
     http: reqwest::Client,
+    disallowed_characters: Regex,
+    page_size: u32,
+    tags: Vec<String>,
+    reader: bool,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    // Reader highlights grouped by the id of their document
+    reader_highlights: OnceCell<HashMap<String, Vec<BookHighlight>>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,26 +40,52 @@ struct Book {
     author: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct BookHighlight {
     text: String,
+    #[serde(default)]
+    note: Option<String>,
     tags: Vec<BookTag>,
+    highlighted_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct BookTag {
     name: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct ReaderListResponse {
+    #[serde(rename = "nextPageCursor")]
+    next_page_cursor: Option<String>,
+    results: Vec<ReaderDocument>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReaderDocument {
+    id: String,
+    title: Option<String>,
+    author: Option<String>,
+    parent_id: Option<String>,
+    content: Option<String>,
+    notes: Option<String>,
+    tags: Option<HashMap<String, serde_json::Value>>,
+    created_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Credentials {
     token: String
 }
 
+type Params = Vec<(&'static str, String)>;
+
 const URL: &str = "https://readwise.io/api/v2";
+const MAX_PAGE_SIZE: u32 = 1000;
+const READER_URL: &str = "https://readwise.io/api/v3";
 
 impl ReadwiseClient {
-    pub async fn new() -> Result<ReadwiseClient> {
+    pub async fn new(settings: ReadwiseSettings) -> Result<ReadwiseClient> {
         let token = Self::load_creds().await?.token;
 
         let mut default_headers = header::HeaderMap::new();
@@ -55,91 +93,223 @@ impl ReadwiseClient {
         default_headers.insert("Content-Type", HeaderValue::from_str("application/json")?);
         default_headers.insert("Authorization", HeaderValue::from_str(&format!("Token {token}"))?);
 
-        let http = reqwest::Client::builder()
-            .default_headers(default_headers)
-            .connection_verbose(true)
-            .build()?;
+        let http = util::http_client(default_headers)?;
 
-        Ok(ReadwiseClient { http })
+        let pattern = format!("[^{}]", settings.allowed_characters);
+        let disallowed_characters = Regex::new(&pattern)
+            .with_context(|| format!("Invalid readwise allowed_characters '{}'", settings.allowed_characters))?;
+
+        if !(1..=MAX_PAGE_SIZE).contains(&settings.page_size) {
+            bail!("Readwise page_size must be between 1 and {MAX_PAGE_SIZE}, got {}", settings.page_size);
+        }
+
+        Ok(ReadwiseClient {
+            http,
+            disallowed_characters,
+            page_size: settings.page_size,
+            tags: settings.tags,
+            reader: settings.reader,
+            since: settings.since,
+            until: settings.until,
+            reader_highlights: OnceCell::new(),
+        })
     }
 
     async fn load_creds() -> Result<Credentials> {
-        util::load_json_config("readwise").await
+        util::load_credentials("readwise", &[("token", "READWISE_TOKEN")]).await
             .with_context(|| "Failed to load JSON config for 'readwise'")
     }
 
+    pub async fn check_auth(&self) -> Result<()> {
+        util::count_request(Service::Readwise);
+        self.http.get(format!("{URL}/auth/"))
+            .send().await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    pub fn is_date_filtered(&self) -> bool {
+        self.since.is_some() || self.until.is_some()
+    }
+
     pub async fn get_words(&self, book: &model::Book) -> Result<Vec<Word>> {
-        let pink_tag =
-            |highlight: &BookHighlight| highlight.tags.iter().any(|tag| tag.name == "pink");
+        let mut pages = match &book.source {
+            BookSource::Readwise => self.get_highlights(book.id),
+            BookSource::Reader(document_id) => self.get_reader_highlights(document_id),
+        };
 
-        Ok(self
-            .get_highlights(book.id).await?
-            .into_iter()
-            .filter(pink_tag)
-            .map(|highlight| highlight.text)
-            .map(|word| ReadwiseClient::transform_word(&word))
-            .unique()
-            .map(|text| Word::from_text(&text))
-            .collect())
+        let has_accepted_tag =
+            |highlight: &BookHighlight| highlight.tags.iter().any(|tag| self.tags.contains(&tag.name));
+
+        // The same word can be highlighted several times under different tags,
+        // so dedup happens on the transformed text rather than on highlights.
+        // The note of the highlight usually holds the sentence the word was highlighted in.
+        let mut texts = HashSet::new();
+        let mut words = Vec::new();
+        while let Some(page) = pages.try_next().await? {
+            for highlight in page.into_iter().filter(has_accepted_tag).filter(|highlight| self.in_date_range(highlight)) {
+                let text = self.transform_word(&highlight.text);
+
+                if texts.insert(text.to_owned()) {
+                    words.push(Word {
+                        context: highlight.note.map(|note| note.trim().to_owned()).filter(|note| !note.is_empty()),
+                        ..Word::from_text(&text)
+                    });
+                }
+            }
+        }
+
+        Ok(words)
     }
 
-    fn transform_word(word: &str) -> String {
+    // Reader highlights can't be filtered by the API, so the range is checked here as well
+    fn in_date_range(&self, highlight: &BookHighlight) -> bool {
+        match highlight.highlighted_at {
+            Some(highlighted_at) => self.since.is_none_or(|since| highlighted_at > since)
+                && self.until.is_none_or(|until| highlighted_at < until),
+            None => !self.is_date_filtered(),
+        }
+    }
+
+    fn transform_word(&self, word: &str) -> String {
         let word = word.to_lowercase();
-        let regex = regex::Regex::new("[^A-Za-z\\s-]").unwrap();
-        regex.replace_all(&word, "").to_string()
+        self.disallowed_characters.replace_all(&word, "").to_string()
     }
 
     pub async fn get_books(&self) -> Result<Vec<model::Book>> {
-        Ok(self.get_list_data::<Book>("/books", &HashMap::new())
-            .await?
+        let mut books: Vec<model::Book> = self.get_list_pages::<Book>("/books", Params::new())
+            .try_concat().await?
+            .into_iter()
+            .map(|book| model::Book { id: book.id, author: book.author, title: book.title, source: BookSource::Readwise })
+            .collect();
+
+        if self.reader {
+            books.extend(self.get_reader_books().await?);
+        }
+
+        Ok(books)
+    }
+
+    async fn get_reader_books(&self) -> Result<Vec<model::Book>> {
+        let highlighted = self.reader_highlights().await?;
+
+        Ok(self.get_reader_document_pages(None)
+            .try_concat().await?
             .into_iter()
-            .map(|book| model::Book { id: book.id, author: book.author, title: book.title })
+            .filter(|document| highlighted.contains_key(&document.id))
+            .map(|document| model::Book {
+                id: reader_book_id(&document.id),
+                title: document.title.unwrap_or_else(|| document.id.to_owned()),
+                author: document.author,
+                source: BookSource::Reader(document.id),
+            })
             .collect())
     }
 
-    async fn get_highlights(&self, book_id: u64) -> Result<Vec<BookHighlight>> {
-        let book_id_str = format!("{book_id}");
-        let params = &HashMap::from([("book_id", book_id_str.as_str())]);
-        self.get_list_data("/highlights", params).await
+    fn get_reader_highlights<'a>(&'a self, document_id: &'a str) -> BoxStream<'a, Result<Vec<BookHighlight>>> {
+        stream::once(async move {
+            Ok(self.reader_highlights().await?.get(document_id).cloned().unwrap_or_default())
+        }).boxed()
     }
 
-    async fn get_list_data<T: DeserializeOwned>(
-        &self,
-        path: &str,
-        params: &HashMap<&str, &str>,
-    ) -> Result<Vec<T>> {
-        let mut page = 1;
-        let mut results: Vec<T> = Vec::new();
-
-        loop {
-            let page_str = format!("{page}");
-            let mut params = params.clone();
-            params.insert("page", &page_str);
-            params.insert("page_size", "1000");
-
-            let mut response: ListResponse<T> = self.make_request(path, &params).await?;
-            page += 1;
-            results.append(&mut response.results);
-
-            match response.next {
-                Some(_) => (),
-                None => break Ok(results),
+    // Reader can't filter highlights by document, so every highlight is fetched once per client
+    async fn reader_highlights(&self) -> Result<&HashMap<String, Vec<BookHighlight>>> {
+        self.reader_highlights.get_or_try_init(|| async {
+            let mut highlights: HashMap<String, Vec<BookHighlight>> = HashMap::new();
+
+            let mut pages = self.get_reader_document_pages(Some("highlight"));
+            while let Some(page) = pages.try_next().await? {
+                for highlight in page {
+                    let Some(document_id) = highlight.parent_id else { continue };
+
+                    highlights.entry(document_id).or_default().push(BookHighlight {
+                        text: highlight.content.unwrap_or_default(),
+                        note: highlight.notes,
+                        highlighted_at: highlight.created_at,
+                        tags: highlight.tags.unwrap_or_default().into_keys()
+                            .map(|name| BookTag { name })
+                            .collect(),
+                    });
+                }
+            }
+
+            Ok(highlights)
+        }).await
+    }
+
+    fn get_reader_document_pages(&self, category: Option<&'static str>) -> BoxStream<'_, Result<Vec<ReaderDocument>>> {
+        // `None` once the last page was fetched, `Some(None)` before the first one
+        let first_page: Option<Option<String>> = Some(None);
+
+        stream::try_unfold(first_page, move |cursor| async move {
+            let Some(cursor) = cursor else { return Ok(None) };
+
+            let mut params = Params::new();
+            if let Some(category) = category {
+                params.push(("category", category.to_owned()));
+            }
+            if let Some(cursor) = cursor {
+                params.push(("pageCursor", cursor));
             }
+
+            let response: ReaderListResponse = self.make_request(&format!("{READER_URL}/list/"), &params).await?;
+
+            Ok(Some((response.results, response.next_page_cursor.map(Some))))
+        }).boxed()
+    }
+
+    fn get_highlights(&self, book_id: u64) -> BoxStream<'_, Result<Vec<BookHighlight>>> {
+        let mut params = vec![("book_id", book_id.to_string())];
+        if let Some(since) = self.since {
+            params.push(("highlighted_at__gt", since.to_rfc3339()));
         }
+        if let Some(until) = self.until {
+            params.push(("highlighted_at__lt", until.to_rfc3339()));
+        }
+
+        self.get_list_pages("/highlights", params)
+    }
+
+    // Pages are yielded as they arrive, so large libraries don't have to be held in memory at once
+    fn get_list_pages<T: DeserializeOwned + Send + 'static>(&self, path: &str, mut params: Params) -> BoxStream<'_, Result<Vec<T>>> {
+        params.push(("page_size", self.page_size.to_string()));
+        let first_page = Some((format!("{URL}{path}"), params));
+
+        stream::try_unfold(first_page, move |page| async move {
+            let Some((url, params)) = page else { return Ok(None) };
+
+            let response: ListResponse<T> = self.make_request(&url, &params).await?;
+
+            // The next URL already carries the filters and the page cursor
+            let next_page = match response.next {
+                Some(next) if next == url => bail!("Readwise returned the same page '{url}' as the next one"),
+                next => next.map(|next| (next, Params::new())),
+            };
+
+            Ok(Some((response.results, next_page)))
+        }).boxed()
     }
 
     async fn make_request<T: DeserializeOwned>(
         &self,
-        path: &str,
-        params: &HashMap<&str, &str>,
+        url: &str,
+        params: &[(&str, String)],
     ) -> Result<T> {
         for _ in 1..=3 {
-            let url = format!("{URL}{path}");
             info!("Requesting {url}");
 
-            let request = self.http.get(&url).query(params);
+            let request = self.http.get(url).query(params);
+            util::count_request(Service::Readwise);
 
-            let response = request.send().await?;
+            let response = match request.send().await {
+                Ok(response) => response,
+                Err(err) if err.is_timeout() => {
+                    warn!("Request to {url} failed with {}, retrying", util::describe_request_error(&err));
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
 
             if response.status() != StatusCode::TOO_MANY_REQUESTS {
                 return Ok(response.json().await?);
@@ -152,6 +322,15 @@ impl ReadwiseClient {
                 tokio::time::sleep(time::Duration::from_secs(retry_after)).await;
             }
         }
-        panic!("Failed to get response from readwise in time");
+        bail!("Failed to get response from readwise for '{url}' in time");
     }
 }
+
+// Reader documents have string ids, while cached words and decks are keyed by a numeric book id.
+// FNV-1a keeps the id stable between runs; the high bit keeps it clear of the small v2 ids.
+fn reader_book_id(document_id: &str) -> u64 {
+    let hash = document_id.bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+
+    (hash & ((1 << 48) - 1)) | (1 << 48)
+}
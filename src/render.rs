@@ -0,0 +1,307 @@
+use anyhow::{Context, Result};
+use handlebars::Handlebars;
+use itertools::Itertools;
+use maud::{html, PreEscaped, DOCTYPE};
+
+use crate::model::{Book, Definition, DefinitionCategory, Definitions, Word};
+use crate::settings::{ExamplesStyle, RenderingSettings};
+
+const STUDY_SHEET_STYLE: &str = "
+body { font-family: Georgia, serif; max-width: 50em; margin: 2em auto; padding: 0 1em; line-height: 1.4; }
+nav ol { columns: 4 10em; font-size: 0.9em; }
+section.word { border-top: 1px solid #ccc; break-inside: avoid; }
+section.word h2 { margin-bottom: 0.2em; }
+.phonetic, .grammatical-features { color: #555; }
+@media print { nav { display: none; } }
+";
+
+const CARD_TEMPLATE: &str = "card";
+
+#[derive(Clone)]
+pub struct CardRenderer {
+    settings: RenderingSettings,
+    templates: Option<Handlebars<'static>>,
+}
+
+impl CardRenderer {
+    pub fn new(settings: RenderingSettings) -> Result<CardRenderer> {
+        let templates = match &settings.template_path {
+            Some(path) => {
+                // Not strict: empty fields of Word are null or missing, so templates must be able to test for them
+                let mut templates = Handlebars::new();
+                templates.register_template_file(CARD_TEMPLATE, path)
+                    .with_context(|| format!("Failed to load card template from '{path}'"))?;
+                Some(templates)
+            }
+            None => None,
+        };
+
+        Ok(CardRenderer { settings, templates })
+    }
+
+    pub fn back_html(&self, word: &Word) -> Result<String> {
+        match &self.templates {
+            Some(templates) => templates.render(CARD_TEMPLATE, word)
+                .with_context(|| format!("Failed to render card template for '{word}'")),
+            None => self.default_back_html(word),
+        }
+    }
+
+    // The front of a reverse card is the translation, so the back starts with the word itself
+    pub fn reverse_back_html(&self, word: &Word) -> Result<String> {
+        let back_text = self.back_html(word)?;
+
+        Ok(html! {
+            p class="word" { b { (word.text) } }
+            (PreEscaped(back_text))
+        }.into_string())
+    }
+
+    fn default_back_html(&self, word: &Word) -> Result<String> {
+        let max_examples = self.settings.max_examples_per_definition.unwrap_or(usize::MAX);
+        let categories = word.definitions.as_ref()
+            .map(|definitions| self.ordered_definitions(definitions))
+            .unwrap_or_default();
+
+        let back_text = html! {
+            @if let Some(translation) = &word.translation {
+                p { (translation) }
+            }
+
+            @if let Some(phonetic) = word.phonetic.as_ref().filter(|_| self.settings.show_phonetic) {
+                p { span class="phonetic" { "/" (phonetic) "/" } }
+            }
+
+            @if let Some(audio_file) = word.audio_file.as_ref().filter(|_| self.settings.show_audio) {
+                p class="audio" { "[sound:" (audio_file) "]" }
+            }
+
+            @if let Some(word_family) = word.word_family.as_ref().filter(|_| self.settings.show_word_family) {
+                p class="word-family" { "Word family: " (word_family.join(", ")) }
+            }
+
+            @if self.settings.show_see_also && !word.see_also.is_empty() {
+                p class="see-also" { "See also: " (word.see_also.join(", ")) }
+            }
+
+            @if !word.inflections.is_empty() {
+                p class="inflections" { "Also highlighted as: " (word.inflections.join(", ")) }
+            }
+
+            @if self.settings.show_thesaurus {
+                @if let Some(synonyms) = &word.synonyms {
+                    p class="synonyms" { "Synonyms: " (synonyms.join(", ")) }
+                }
+
+                @if let Some(antonyms) = &word.antonyms {
+                    p class="antonyms" { "Antonyms: " (antonyms.join(", ")) }
+                }
+            }
+
+            @if !categories.is_empty() {
+                ol type="I" {
+                    @for (category, definitions) in categories {
+                        @let special = self.settings.trailing_categories.contains(category);
+                        li class={ "category category-" (category) @if special { " special-category" } } {
+                            @match self.settings.category_labels.get(category) {
+                                Some(label) => p { (label) },
+                                None => p { (category) },
+                            }
+
+                            ol type="1" {
+                                @for definition in definitions {
+                                    li {
+                                        @if let Some(text) = &definition.definition {
+                                            p {
+                                                @if !definition.grammatical_features.is_empty() {
+                                                    span class="grammatical-features" {
+                                                        i { (definition.grammatical_features.join(", ")) } " "
+                                                    }
+                                                }
+                                                @if self.settings.show_registers && !definition.registers.is_empty() {
+                                                    span class="registers" {
+                                                        "[" (definition.registers.join(", ")) "] "
+                                                    }
+                                                }
+                                                (text)
+                                            }
+                                        }
+
+                                        @let examples = definition.examples.iter().take(max_examples).collect_vec();
+
+                                        @match self.settings.examples_style {
+                                            ExamplesStyle::Bulleted => ul {
+                                                @for example in &examples {
+                                                    li { (example) }
+                                                }
+                                            },
+                                            ExamplesStyle::Numbered => ol type="a" {
+                                                @for example in &examples {
+                                                    li { (example) }
+                                                }
+                                            },
+                                            ExamplesStyle::Inline => @if !examples.is_empty() {
+                                                p { i { (examples.iter().join(", ")) } }
+                                            },
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            @if self.settings.show_etymology && !word.etymologies.is_empty() {
+                p class="etymology" { small { "Origin: " (word.etymologies.join(" ")) } }
+            }
+
+            @if let Some(context) = word.context.as_ref().filter(|_| self.settings.show_context) {
+                blockquote class="context" { i { (context) } }
+            }
+        }.into_string();
+
+        Ok(back_text)
+    }
+
+    fn ordered_definitions<'a>(&self, definitions: &'a Definitions) -> Vec<(&'a DefinitionCategory, Vec<&'a Definition>)> {
+        let trailing_position = |category: &DefinitionCategory| {
+            self.settings.trailing_categories.iter().position(|trailing| trailing == category)
+        };
+        let per_category = self.settings.max_definitions_per_category.unwrap_or(usize::MAX);
+        let mut remaining = self.settings.max_definitions.unwrap_or(usize::MAX);
+
+        definitions.iter()
+            .sorted_by_key(|(category, _)| (trailing_position(category), *category))
+            .map(|(category, definitions)| {
+                // Cross-reference-only senses have no text to show
+                let definitions = definitions.iter()
+                    .filter(|definition| definition.definition.is_some())
+                    .take(per_category.min(remaining))
+                    .collect_vec();
+                remaining -= definitions.len();
+                (category, definitions)
+            })
+            .filter(|(_, definitions)| !definitions.is_empty())
+            .collect()
+    }
+
+    pub fn study_sheet_html(&self, book: &Book, words: &[Word]) -> Result<String> {
+        // [sound:] tags are only played by Anki, a browser would show them as text
+        let renderer = CardRenderer { settings: RenderingSettings { show_audio: false, ..self.settings.clone() }, ..self.clone() };
+        let cards = words.iter()
+            .map(|word| renderer.back_html(word))
+            .collect::<Result<Vec<_>>>()?;
+
+        let sheet = html! {
+            (DOCTYPE)
+            html {
+                head {
+                    meta charset="utf-8";
+                    title { (book.title) }
+                    style { (PreEscaped(STUDY_SHEET_STYLE)) }
+                }
+                body {
+                    h1 { (book.title) }
+                    @if let Some(author) = &book.author {
+                        p class="author" { (author) }
+                    }
+
+                    nav {
+                        ol {
+                            @for (index, word) in words.iter().enumerate() {
+                                li { a href={ "#word-" (index) } { (word.text) } }
+                            }
+                        }
+                    }
+
+                    @for (index, (word, card)) in words.iter().zip(cards).enumerate() {
+                        section class="word" id={ "word-" (index) } {
+                            h2 { (word.text) }
+                            (PreEscaped(card))
+                        }
+                    }
+                }
+            }
+        }.into_string();
+
+        Ok(sheet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::model::BookSource;
+
+    fn render(examples_style: ExamplesStyle, examples: &[&str]) -> String {
+        let renderer = CardRenderer::new(RenderingSettings { examples_style, ..RenderingSettings::default() }).unwrap();
+        let definition = Definition {
+            definition: Some("move fast".to_string()),
+            examples: examples.iter().map(|example| example.to_string()).collect(),
+            grammatical_features: Vec::new(),
+            registers: Vec::new(),
+        };
+        let word = Word {
+            definitions: Some(HashMap::from([(DefinitionCategory::Verb, vec![definition])])),
+            ..Word::from_text("run")
+        };
+
+        renderer.back_html(&word).unwrap()
+    }
+
+    fn card(definition_html: &str) -> String {
+        format!(r#"<ol type="I"><li class="category category-verb"><p>verb</p><ol type="1"><li>{definition_html}</li></ol></li></ol>"#)
+    }
+
+    #[test]
+    fn bulleted_examples() {
+        let html = render(ExamplesStyle::Bulleted, &["he ran home", "she runs daily"]);
+
+        assert_eq!(html, card("<p>move fast</p><ul><li>he ran home</li><li>she runs daily</li></ul>"));
+    }
+
+    #[test]
+    fn numbered_examples() {
+        let html = render(ExamplesStyle::Numbered, &["he ran home", "she runs daily"]);
+
+        assert_eq!(html, card(r#"<p>move fast</p><ol type="a"><li>he ran home</li><li>she runs daily</li></ol>"#));
+    }
+
+    #[test]
+    fn inline_examples() {
+        let html = render(ExamplesStyle::Inline, &["he ran home", "she runs daily"]);
+
+        assert_eq!(html, card("<p>move fast</p><p><i>he ran home, she runs daily</i></p>"));
+    }
+
+    #[test]
+    fn inline_without_examples() {
+        let html = render(ExamplesStyle::Inline, &[]);
+
+        assert_eq!(html, card("<p>move fast</p>"));
+    }
+
+    #[test]
+    fn study_sheet_without_audio() {
+        let renderer = CardRenderer::new(RenderingSettings { show_audio: true, ..RenderingSettings::default() }).unwrap();
+        let book = Book { id: 1, title: "Test".to_string(), author: None, source: BookSource::Readwise };
+        let word = Word { audio_file: Some("run.mp3".to_string()), ..Word::from_text("run") };
+
+        assert!(renderer.back_html(&word).unwrap().contains("[sound:run.mp3]"));
+        assert!(!renderer.study_sheet_html(&book, &[word]).unwrap().contains("[sound:"));
+    }
+
+    #[test]
+    fn template_with_missing_optional_field() {
+        let path = std::env::temp_dir().join(format!("anki-words-card-{}-missing-field.hbs", std::process::id()));
+        std::fs::write(&path, "{{text}}{{#if phonetic}} /{{phonetic}}/{{/if}}{{#each inflections}}, {{this}}{{/each}}{{context}}").unwrap();
+        let settings = RenderingSettings { template_path: Some(path.to_string_lossy().into_owned()), ..RenderingSettings::default() };
+        let renderer = CardRenderer::new(settings);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(renderer.unwrap().back_html(&Word::from_text("run")).unwrap(), "run");
+    }
+}
@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+
+use crate::model::{Book, DefinitionCategory};
+use crate::util;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub dictionaries: Vec<DictionarySource>,
+    pub readwise: ReadwiseSettings,
+    pub oxford: OxfordSettings,
+    pub wiktionary: WiktionarySettings,
+    pub translation: TranslationSettings,
+    pub anki: AnkiSettings,
+    pub rendering: RenderingSettings,
+    pub processing: ProcessingSettings,
+    pub storage: StorageSettings,
+    pub network: NetworkSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            dictionaries: vec![DictionarySource::Oxford],
+            readwise: ReadwiseSettings::default(),
+            oxford: OxfordSettings::default(),
+            wiktionary: WiktionarySettings::default(),
+            translation: TranslationSettings::default(),
+            anki: AnkiSettings::default(),
+            rendering: RenderingSettings::default(),
+            processing: ProcessingSettings::default(),
+            storage: StorageSettings::default(),
+            network: NetworkSettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DictionarySource {
+    Oxford,
+    Wiktionary,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReadwiseSettings {
+    #[serde(deserialize_with = "one_or_many")]
+    pub tags: Vec<String>,
+    pub reader: bool,
+    pub allowed_characters: String,
+    pub page_size: u32,
+    #[serde(skip)]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Default for ReadwiseSettings {
+    fn default() -> Self {
+        ReadwiseSettings {
+            tags: vec!["pink".to_string()],
+            reader: false,
+            allowed_characters: r"\p{L}\p{M}\s'-".to_string(),
+            page_size: 1000,
+            since: None,
+            until: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OxfordSettings {
+    pub max_retries: u32,
+    pub backoff_base_ms: u64,
+    pub word_family: bool,
+    pub thesaurus: bool,
+    pub standard_categories_only: bool,
+    pub requests_per_minute: Option<u32>,
+    pub lemma_cache: bool,
+    pub definition_strategy: DefinitionStrategy,
+    pub source_language: String,
+    pub languages: Vec<String>,
+}
+
+impl Default for OxfordSettings {
+    fn default() -> Self {
+        OxfordSettings {
+            max_retries: 3,
+            backoff_base_ms: 1000,
+            word_family: false,
+            thesaurus: false,
+            standard_categories_only: false,
+            requests_per_minute: None,
+            lemma_cache: true,
+            definition_strategy: DefinitionStrategy::default(),
+            source_language: "en".to_string(),
+            languages: Vec::new(),
+        }
+    }
+}
+
+impl OxfordSettings {
+    // Only English has dialects, the entries of other languages share the source language code
+    pub fn entry_languages(&self) -> Vec<String> {
+        match self.source_language.as_str() {
+            _ if !self.languages.is_empty() => self.languages.clone(),
+            "en" => vec!["en-us".to_string(), "en-gb".to_string()],
+            source_language => vec![source_language.to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WiktionarySettings {
+    // Wiktionary language code of the highlighted words, entries of other languages are ignored
+    pub language: String,
+}
+
+impl Default for WiktionarySettings {
+    fn default() -> Self {
+        WiktionarySettings {
+            language: "en".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DefinitionStrategy {
+    #[default]
+    First,
+    Shortest,
+    Longest,
+    All,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TranslationSettings {
+    pub provider: TranslationProvider,
+    pub empty_policy: EmptyTranslationPolicy,
+    pub cache: bool,
+    pub token_renewal_margin_secs: i64,
+    pub normalize: bool,
+    pub lowercase_first_letter: bool,
+}
+
+impl Default for TranslationSettings {
+    fn default() -> Self {
+        TranslationSettings {
+            provider: TranslationProvider::default(),
+            empty_policy: EmptyTranslationPolicy::default(),
+            cache: true,
+            token_renewal_margin_secs: 300,
+            normalize: true,
+            lowercase_first_letter: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TranslationProvider {
+    #[default]
+    Google,
+    Deepl,
+    GoogleFree,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyTranslationPolicy {
+    #[default]
+    Fail,
+    EmptyString,
+    Skip,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AnkiSettings {
+    pub endpoint: String,
+    pub note: AnkiNoteSettings,
+    pub dedupe_across_decks: bool,
+    pub global_dedupe: bool,
+    pub tags: AnkiTagSettings,
+    pub sync: bool,
+    pub deck_name_template: String,
+    pub deck_name: Option<String>,
+    pub max_retries: u32,
+    pub backoff_base_ms: u64,
+    pub card_direction: CardDirection,
+}
+
+impl Default for AnkiSettings {
+    fn default() -> Self {
+        AnkiSettings {
+            endpoint: "http://localhost:8765".to_string(),
+            note: AnkiNoteSettings::default(),
+            dedupe_across_decks: false,
+            global_dedupe: false,
+            tags: AnkiTagSettings::default(),
+            sync: false,
+            deck_name_template: "{title}".to_string(),
+            deck_name: None,
+            max_retries: 3,
+            backoff_base_ms: 500,
+            card_direction: CardDirection::default(),
+        }
+    }
+}
+
+impl AnkiSettings {
+    pub fn deck_name(&self, book: &Book) -> String {
+        if let Some(deck_name) = &self.deck_name {
+            return deck_name.to_owned();
+        }
+
+        self.deck_name_template
+            .replace("{title}", &book.title)
+            .replace("{author}", book.author.as_deref().unwrap_or("Unknown"))
+            .replace("{id}", &book.id.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CardDirection {
+    #[default]
+    Forward,
+    Reverse,
+    Both,
+}
+
+impl CardDirection {
+    pub fn has_forward(self) -> bool {
+        self != CardDirection::Reverse
+    }
+
+    pub fn has_reverse(self) -> bool {
+        self != CardDirection::Forward
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AnkiTagSettings {
+    pub constant: Vec<String>,
+    pub book: bool,
+    pub categories: bool,
+}
+
+impl Default for AnkiTagSettings {
+    fn default() -> Self {
+        AnkiTagSettings { constant: vec!["anki-words-importer".to_string()], book: true, categories: true }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AnkiNoteSettings {
+    pub model_name: String,
+    pub front_field: String,
+    pub back_field: String,
+}
+
+impl Default for AnkiNoteSettings {
+    fn default() -> Self {
+        AnkiNoteSettings {
+            model_name: "Basic".to_string(),
+            front_field: "Front".to_string(),
+            back_field: "Back".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RenderingSettings {
+    pub examples_style: ExamplesStyle,
+    pub category_labels: HashMap<DefinitionCategory, String>,
+    pub trailing_categories: Vec<DefinitionCategory>,
+    pub max_definitions: Option<usize>,
+    // Settings files written before the per-definition limit still use the old name
+    #[serde(alias = "max_examples")]
+    pub max_examples_per_definition: Option<usize>,
+    pub max_definitions_per_category: Option<usize>,
+    pub show_phonetic: bool,
+    pub show_audio: bool,
+    pub show_word_family: bool,
+    pub show_thesaurus: bool,
+    pub show_see_also: bool,
+    pub show_context: bool,
+    pub show_registers: bool,
+    pub show_etymology: bool,
+    pub template_path: Option<String>,
+}
+
+impl Default for RenderingSettings {
+    fn default() -> Self {
+        RenderingSettings {
+            examples_style: ExamplesStyle::default(),
+            category_labels: HashMap::new(),
+            trailing_categories: vec![DefinitionCategory::Interjection, DefinitionCategory::Idiomatic],
+            max_definitions: None,
+            max_examples_per_definition: None,
+            max_definitions_per_category: None,
+            show_phonetic: true,
+            show_audio: true,
+            show_word_family: true,
+            show_thesaurus: true,
+            show_see_also: false,
+            show_context: true,
+            show_registers: true,
+            show_etymology: true,
+            template_path: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExamplesStyle {
+    #[default]
+    Bulleted,
+    Numbered,
+    Inline,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Preset {
+    Minimal,
+    Full,
+    Rich,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProcessingSettings {
+    pub concurrency: usize,
+    pub retry_incomplete: bool,
+    pub include_categories: Vec<DefinitionCategory>,
+    pub limit: Option<usize>,
+    pub log_interval: usize,
+    pub skip_known: bool,
+    pub group_by_lemma: bool,
+    pub interactive: bool,
+    #[serde(skip)]
+    pub offline: bool,
+    #[serde(skip)]
+    pub assume_yes: bool,
+}
+
+impl Default for ProcessingSettings {
+    fn default() -> Self {
+        ProcessingSettings {
+            concurrency: 4,
+            retry_incomplete: false,
+            include_categories: Vec::new(),
+            limit: None,
+            log_interval: 10,
+            skip_known: false,
+            group_by_lemma: false,
+            interactive: false,
+            offline: false,
+            assume_yes: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct StorageSettings {
+    pub backend: StorageBackend,
+    pub data_dir: Option<String>,
+    pub sqlite_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    Json,
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NetworkSettings {
+    pub proxy: Option<String>,
+    pub connect_timeout_secs: u64,
+    pub timeout_secs: u64,
+    pub request_warn_thresholds: HashMap<Service, usize>,
+    pub connection_verbose: bool,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        NetworkSettings {
+            proxy: None,
+            connect_timeout_secs: 10,
+            timeout_secs: 30,
+            request_warn_thresholds: HashMap::new(),
+            connection_verbose: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Service {
+    Readwise,
+    Oxford,
+    Wiktionary,
+    Translation,
+    Anki,
+}
+
+impl Service {
+    pub const ALL: [Service; 5] = [Service::Readwise, Service::Oxford, Service::Wiktionary, Service::Translation, Service::Anki];
+}
+
+impl Settings {
+    pub async fn load() -> Result<Settings> {
+        Ok(util::load_optional_json_config("settings").await?.unwrap_or_default())
+    }
+
+    pub fn apply_preset(&mut self, preset: Preset) {
+        let rendering = &mut self.rendering;
+
+        match preset {
+            Preset::Minimal => {
+                rendering.max_definitions = Some(1);
+                rendering.max_examples_per_definition = Some(0);
+                rendering.show_phonetic = false;
+                rendering.show_audio = false;
+                rendering.show_word_family = false;
+                rendering.show_thesaurus = false;
+                rendering.show_see_also = false;
+                rendering.show_context = false;
+                rendering.show_registers = false;
+                rendering.show_etymology = false;
+            }
+            // The settings file may have changed the defaults that the other presets change
+            Preset::Full => {
+                *rendering = RenderingSettings { template_path: rendering.template_path.take(), ..RenderingSettings::default() };
+                let defaults = OxfordSettings::default();
+                self.oxford.word_family = defaults.word_family;
+                self.oxford.thesaurus = defaults.thesaurus;
+            }
+            Preset::Rich => {
+                rendering.max_definitions = None;
+                rendering.max_examples_per_definition = None;
+                rendering.max_definitions_per_category = None;
+                rendering.show_phonetic = true;
+                rendering.show_audio = true;
+                rendering.show_word_family = true;
+                rendering.show_thesaurus = true;
+                rendering.show_see_also = true;
+                rendering.show_context = true;
+                rendering.show_registers = true;
+                rendering.show_etymology = true;
+                self.oxford.word_family = true;
+                self.oxford.thesaurus = true;
+            }
+        }
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_preset_restores_defaults() {
+        let mut settings = Settings::default();
+        settings.rendering.template_path = Some("card.hbs".to_string());
+        settings.apply_preset(Preset::Rich);
+        settings.apply_preset(Preset::Minimal);
+
+        settings.apply_preset(Preset::Full);
+
+        let defaults = Settings::default();
+        assert_eq!(settings.rendering.max_definitions, defaults.rendering.max_definitions);
+        assert_eq!(settings.rendering.max_examples_per_definition, defaults.rendering.max_examples_per_definition);
+        assert_eq!(settings.rendering.show_phonetic, defaults.rendering.show_phonetic);
+        assert_eq!(settings.rendering.show_audio, defaults.rendering.show_audio);
+        assert_eq!(settings.rendering.show_context, defaults.rendering.show_context);
+        assert_eq!(settings.oxford.thesaurus, defaults.oxford.thesaurus);
+        assert_eq!(settings.rendering.template_path.as_deref(), Some("card.hbs"));
+    }
+}
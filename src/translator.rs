@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+
+use anyhow::{anyhow, Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, info, warn};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::{db, util};
+
+use crate::deepl_translate::DeepLTranslate;
+use crate::google_free_translate::GoogleFreeTranslate;
+use crate::google_translate::GoogleTranslate;
+use crate::settings::{EmptyTranslationPolicy, TranslationProvider, TranslationSettings};
+
+pub trait Translator: Send + Sync {
+    fn translate<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+
+    // `None` entries are left to be translated one by one, which is all providers without
+    // a batch API do
+    fn translate_batch<'a>(&'a self, queries: &'a [String]) -> BoxFuture<'a, Result<Vec<Option<String>>>> {
+        async move { Ok(vec![None; queries.len()]) }.boxed()
+    }
+
+    // Saves what the translator caches in memory
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        async {}.boxed()
+    }
+
+    // Lets the provenance tell cached translations from fresh ones
+    fn is_cached<'a>(&'a self, _query: &'a str) -> BoxFuture<'a, bool> {
+        async { false }.boxed()
+    }
+}
+
+const CACHE_SAVE_BATCH: usize = 50;
+
+const TARGET_LANGUAGE: &str = "ru";
+
+pub async fn create(settings: &TranslationSettings, source_language: &str) -> Result<Box<dyn Translator>> {
+    let translator: Box<dyn Translator> = match settings.provider {
+        TranslationProvider::Google => Box::new(GoogleTranslate::new(settings.clone(), source_language).await?),
+        TranslationProvider::Deepl => Box::new(DeepLTranslate::new(settings.clone(), source_language).await?),
+        TranslationProvider::GoogleFree => Box::new(GoogleFreeTranslate::new(settings.clone(), source_language)?),
+    };
+
+    let translator: Box<dyn Translator> = if settings.cache {
+        Box::new(CachingTranslator::load(translator, source_language).await?)
+    } else {
+        translator
+    };
+
+    if !settings.normalize {
+        return Ok(translator);
+    }
+
+    // Normalizing on top of the cache also cleans up translations cached before it was enabled
+    Ok(Box::new(NormalizingTranslator { inner: translator, lowercase_first_letter: settings.lowercase_first_letter }))
+}
+
+pub struct NormalizingTranslator {
+    inner: Box<dyn Translator>,
+    lowercase_first_letter: bool,
+}
+
+impl NormalizingTranslator {
+    async fn translate(&self, query: &str) -> Result<Option<String>> {
+        let translation = self.inner.translate(query).await?;
+
+        Ok(translation.map(|translation| self.normalize(query, translation)))
+    }
+
+    async fn translate_batch(&self, queries: &[String]) -> Result<Vec<Option<String>>> {
+        Ok(self.inner.translate_batch(queries).await?
+            .into_iter()
+            .zip(queries)
+            .map(|(translation, query)| translation.map(|translation| self.normalize(query, translation)))
+            .collect())
+    }
+
+    fn normalize(&self, query: &str, raw: String) -> String {
+        let decoded = util::decode_html_entities(&raw);
+        let mut translation = decoded.trim().trim_end_matches(['.', ',', ';', ':']).trim_end().to_string();
+
+        if self.lowercase_first_letter {
+            let mut chars = translation.chars();
+            if let Some(first) = chars.next() {
+                translation = first.to_lowercase().chain(chars).collect();
+            }
+        }
+
+        if translation != raw {
+            debug!("Normalized translation of '{query}' from '{raw}' to '{translation}'");
+        }
+
+        translation
+    }
+}
+
+impl Translator for NormalizingTranslator {
+    fn translate<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        self.translate(query).boxed()
+    }
+
+    fn translate_batch<'a>(&'a self, queries: &'a [String]) -> BoxFuture<'a, Result<Vec<Option<String>>>> {
+        self.translate_batch(queries).boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        self.inner.flush()
+    }
+
+    fn is_cached<'a>(&'a self, query: &'a str) -> BoxFuture<'a, bool> {
+        self.inner.is_cached(query)
+    }
+}
+
+// Translations keyed by language pair, e.g. "en-ru", and then by source text
+type TranslationCache = HashMap<String, HashMap<String, String>>;
+
+// Saved in batches of `CACHE_SAVE_BATCH` new translations and once more by `flush`
+struct CacheState {
+    translations: TranslationCache,
+    unsaved: usize,
+}
+
+pub struct CachingTranslator {
+    inner: Box<dyn Translator>,
+    cache: Mutex<CacheState>,
+    key: String,
+}
+
+impl CachingTranslator {
+    pub async fn load(inner: Box<dyn Translator>, source_language: &str) -> Result<CachingTranslator> {
+        let filename = Self::filename();
+
+        let mut cache: TranslationCache = match fs::read_to_string(&filename).await {
+            Ok(json) => serde_json::from_str(&json)
+                .with_context(|| format!("Couldn't deserialize translations from file at '{filename}'"))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => TranslationCache::new(),
+            Err(err) => return Err(err).with_context(|| format!("Couldn't read translations from '{filename}'")),
+        };
+
+        // Translations used to be keyed by the target language only, when every source was English
+        if let Some(translations) = cache.remove(TARGET_LANGUAGE) {
+            cache.entry(format!("en-{TARGET_LANGUAGE}")).or_default().extend(translations);
+        }
+
+        let key = format!("{source_language}-{TARGET_LANGUAGE}");
+
+        let cache = CacheState { translations: cache, unsaved: 0 };
+
+        Ok(CachingTranslator { inner, cache: Mutex::new(cache), key })
+    }
+
+    async fn translate(&self, query: &str) -> Result<Option<String>> {
+        let cached = self.cache.lock().await
+            .translations
+            .get(&self.key)
+            .and_then(|translations| translations.get(query))
+            .cloned();
+
+        if let Some(translation) = cached {
+            debug!("Using cached translation for '{query}'");
+            return Ok(Some(translation));
+        }
+
+        let translation = self.inner.translate(query).await?;
+
+        if let Some(translation) = translation.as_ref().filter(|translation| !translation.is_empty()) {
+            let mut cache = self.cache.lock().await;
+            cache.translations.entry(self.key.clone())
+                .or_default()
+                .insert(query.to_string(), translation.to_owned());
+
+            cache.unsaved += 1;
+            if cache.unsaved >= CACHE_SAVE_BATCH {
+                Self::save(&mut cache).await;
+            }
+        }
+
+        Ok(translation)
+    }
+
+    async fn translate_batch(&self, queries: &[String]) -> Result<Vec<Option<String>>> {
+        let mut translations = {
+            let cache = self.cache.lock().await;
+            let cached = cache.translations.get(&self.key);
+
+            queries.iter()
+                .map(|query| cached.and_then(|translations| translations.get(query)).cloned())
+                .collect::<Vec<_>>()
+        };
+
+        let missing = queries.iter().zip(&translations)
+            .filter(|(_, translation)| translation.is_none())
+            .map(|(query, _)| query.to_owned())
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            return Ok(translations);
+        }
+
+        let mut fresh = missing.iter().zip(self.inner.translate_batch(&missing).await?);
+        for translation in translations.iter_mut().filter(|translation| translation.is_none()) {
+            *translation = fresh.next().and_then(|(_, translation)| translation);
+        }
+
+        let mut cache = self.cache.lock().await;
+        let cached = cache.translations.entry(self.key.clone()).or_default();
+        let mut added = 0;
+        for (query, translation) in queries.iter().zip(&translations) {
+            if let Some(translation) = translation.as_ref().filter(|translation| !translation.is_empty()) {
+                cached.insert(query.to_owned(), translation.to_owned());
+                added += 1;
+            }
+        }
+
+        cache.unsaved += added;
+        if cache.unsaved >= CACHE_SAVE_BATCH {
+            Self::save(&mut cache).await;
+        }
+
+        Ok(translations)
+    }
+
+    async fn is_cached(&self, query: &str) -> bool {
+        self.cache.lock().await
+            .translations
+            .get(&self.key)
+            .is_some_and(|translations| translations.contains_key(query))
+    }
+
+    async fn flush(&self) {
+        let mut cache = self.cache.lock().await;
+        if cache.unsaved > 0 {
+            Self::save(&mut cache).await;
+        }
+    }
+
+    // A failed save only costs requests on the next run, so it doesn't fail the word
+    async fn save(cache: &mut CacheState) {
+        match Self::write(&cache.translations).await {
+            Ok(()) => cache.unsaved = 0,
+            Err(err) => warn!("Failed to save the translation cache: {err:#}"),
+        }
+    }
+
+    async fn write(cache: &TranslationCache) -> Result<()> {
+        let filename = Self::filename();
+        let tmp_filename = format!("{filename}.tmp");
+
+        fs::create_dir_all(db::data_dir()).await
+            .with_context(|| format!("Failed to create dir '{}'", db::data_dir()))?;
+
+        let json = serde_json::to_string(cache)
+            .context("Failed to serialize translations")?;
+
+        fs::write(&tmp_filename, json).await
+            .with_context(|| format!("Failed to write file {tmp_filename}"))?;
+        fs::rename(&tmp_filename, &filename).await
+            .with_context(|| format!("Failed to move {tmp_filename} to {filename}"))?;
+
+        Ok(())
+    }
+
+    fn filename() -> String {
+        format!("{}/translations.json", db::data_dir())
+    }
+}
+
+impl Translator for CachingTranslator {
+    fn translate<'a>(&'a self, query: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        self.translate(query).boxed()
+    }
+
+    fn translate_batch<'a>(&'a self, queries: &'a [String]) -> BoxFuture<'a, Result<Vec<Option<String>>>> {
+        self.translate_batch(queries).boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<'_, ()> {
+        self.flush().boxed()
+    }
+
+    fn is_cached<'a>(&'a self, query: &'a str) -> BoxFuture<'a, bool> {
+        self.is_cached(query).boxed()
+    }
+}
+
+pub fn apply_empty_policy(query: &str, translation: Option<String>, policy: EmptyTranslationPolicy) -> Result<Option<String>> {
+    match (translation, policy) {
+        (Some(translation), _) => Ok(Some(translation)),
+        (None, EmptyTranslationPolicy::Fail) => Err(anyhow!("No translation")),
+        (None, EmptyTranslationPolicy::EmptyString) => Ok(Some(String::new())),
+        (None, EmptyTranslationPolicy::Skip) => {
+            info!("No translation for '{query}', skipping it");
+            Ok(None)
+        }
+    }
+}
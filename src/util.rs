@@ -1,23 +1,368 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
 use tokio::fs;
 use serde::de::DeserializeOwned;
-use anyhow::{anyhow, Context, Result};
-use tokio::io::AsyncReadExt;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::error::Category;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use log::{info, log_enabled, warn, Level};
+use reqwest::header::HeaderMap;
+use reqwest::{Proxy, Url};
+
+use crate::settings::{NetworkSettings, Service};
+
+static HTTP_CONFIG: OnceLock<HttpConfig> = OnceLock::new();
+// Indexed by the position of the service in `Service::ALL`
+static REQUEST_COUNTS: [AtomicUsize; Service::ALL.len()] = [const { AtomicUsize::new(0) }; Service::ALL.len()];
+
+struct HttpConfig {
+    proxy: Option<Url>,
+    no_proxy: Vec<String>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    request_warn_thresholds: HashMap<Service, usize>,
+    connection_verbose: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        let settings = NetworkSettings::default();
+
+        HttpConfig {
+            proxy: None,
+            no_proxy: Vec::new(),
+            connect_timeout: Duration::from_secs(settings.connect_timeout_secs),
+            timeout: Duration::from_secs(settings.timeout_secs),
+            request_warn_thresholds: settings.request_warn_thresholds,
+            connection_verbose: settings.connection_verbose,
+        }
+    }
+}
 
 pub async fn load_json_config<T: DeserializeOwned>(file_id: &str) -> Result<T> {
-    let project_dirs = directories::ProjectDirs::from("net", "oksenenko", "anki-words-importer")
-        .ok_or(anyhow!("Failed to get config dir path for '{file_id}'"))?;
+    load_json_file(&config_file_path(file_id)?).await
+}
 
-    let file_path = project_dirs.config_dir().join(file_id);
+// Credentials come from the config file when it exists, otherwise from environment variables
+// mapped to the credential fields, e.g. `[("token", "READWISE_TOKEN")]`
+pub async fn load_credentials<T: DeserializeOwned>(file_id: &str, env_vars: &[(&str, &str)]) -> Result<T> {
+    if has_config(file_id).await? {
+        return load_json_config(file_id).await;
+    }
 
-    let mut file = fs::File::open(&file_path).await
-        .with_context(|| format!("Couldn't open JSON config file at '{}'", file_path.display()))?;
+    let values: Option<serde_json::Map<String, serde_json::Value>> = env_vars.iter()
+        .map(|(field, var)| Some((field.to_string(), std::env::var(var).ok()?.into())))
+        .collect();
+
+    match values {
+        Some(values) if !env_vars.is_empty() => serde_json::from_value(values.into())
+            .with_context(|| format!("Couldn't build '{file_id}' credentials from environment variables")),
+        _ => {
+            let vars = env_vars.iter().map(|(_, var)| *var).collect::<Vec<_>>().join(", ");
+            bail!("Neither a filled in '{}' config file nor the environment variables {vars} are set",
+                config_file_path(file_id)?.display())
+        }
+    }
+}
+
+// Templates written by `init` still have "<...>" placeholders, they mustn't shadow the environment variables
+async fn has_config(file_id: &str) -> Result<bool> {
+    let file_path = config_file_path(file_id)?;
+    if !file_exists(&file_path).await? {
+        return Ok(false);
+    }
+
+    // Invalid files count as filled in, so loading them reports the error
+    let is_template = load_json_file::<serde_json::Value>(&file_path).await
+        .is_ok_and(|value| has_placeholder(&value));
+    if is_template {
+        warn!("Ignoring '{}', it still has the placeholder values written by init", file_path.display());
+    }
+
+    Ok(!is_template)
+}
+
+fn has_placeholder(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::String(text) => text.starts_with('<') && text.ends_with('>'),
+        serde_json::Value::Array(values) => values.iter().any(has_placeholder),
+        serde_json::Value::Object(fields) => fields.values().any(has_placeholder),
+        _ => false,
+    }
+}
+
+pub async fn load_json_file<T: DeserializeOwned>(file_path: &Path) -> Result<T> {
+    let mut file = match fs::File::open(&file_path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => bail!("JSON config file '{}' doesn't exist", file_path.display()),
+        Err(err) => return Err(err).with_context(|| format!("Couldn't open JSON config file at '{}'", file_path.display())),
+    };
 
     let mut buf = String::new();
     file.read_to_string(&mut buf).await
         .with_context(|| format!("Couldn't read from JSON config file at '{}'", file_path.display()))?;
 
-    let result = serde_json::from_str(&buf)
-        .with_context(|| format!("Couldn't deserialize JSON config file at '{}'", file_path.display()))?;
+    serde_json::from_str(&buf)
+        .map_err(|err| anyhow!("{}", describe_json_error(&buf, &err)))
+        .with_context(|| format!("Couldn't deserialize JSON config file at '{}'", file_path.display()))
+}
+
+// serde only reports the position, so the offending line is quoted to show which field is wrong
+fn describe_json_error(json: &str, err: &serde_json::Error) -> String {
+    let kind = match err.classify() {
+        Category::Syntax | Category::Eof => "invalid JSON",
+        Category::Data => "unexpected value",
+        Category::Io => "read error",
+    };
+
+    match json.lines().nth(err.line().saturating_sub(1)).map(str::trim).filter(|line| !line.is_empty()) {
+        Some(line) => format!("{kind}: {err}\n  {line}"),
+        None => format!("{kind}: {err}"),
+    }
+}
+
+async fn file_exists(file_path: &Path) -> Result<bool> {
+    match fs::metadata(file_path).await {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err).with_context(|| format!("Couldn't access '{}'", file_path.display())),
+    }
+}
+
+pub async fn load_optional_json_config<T: DeserializeOwned>(file_id: &str) -> Result<Option<T>> {
+    if !has_config(file_id).await? {
+        return Ok(None);
+    }
+
+    load_json_config(file_id).await.map(Some)
+}
+
+// Returns false when the file already exists, so user edits are never overwritten
+pub async fn write_config_template(file_id: &str, contents: &serde_json::Value) -> Result<bool> {
+    let file_path = config_file_path(file_id)?;
+    if file_exists(&file_path).await? {
+        return Ok(false);
+    }
+
+    if let Some(dir) = file_path.parent() {
+        fs::create_dir_all(dir).await
+            .with_context(|| format!("Failed to create config dir '{}'", dir.display()))?;
+    }
+
+    write_private_file(&file_path, serde_json::to_string_pretty(contents)?).await
+        .with_context(|| format!("Failed to write config file '{}'", file_path.display()))?;
+
+    Ok(true)
+}
+
+// Credentials and tokens are only readable by the user. Writing through a temp file and a rename
+// never leaves a half written file behind.
+pub async fn write_private_file(file_path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut tmp_path = file_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&tmp_path).await
+        .with_context(|| format!("Failed to create file '{}'", Path::new(&tmp_path).display()))?;
+    file.write_all(contents.as_ref()).await?;
+    file.sync_all().await?;
+
+    fs::rename(&tmp_path, file_path).await
+        .with_context(|| format!("Failed to move '{}' to '{}'", Path::new(&tmp_path).display(), file_path.display()))
+}
+
+pub fn config_file_path(file_id: &str) -> Result<PathBuf> {
+    Ok(project_dirs(file_id)?.config_dir().join(file_id))
+}
+
+pub fn cache_file_path(file_id: &str) -> Result<PathBuf> {
+    Ok(project_dirs(file_id)?.cache_dir().join(file_id))
+}
+
+pub fn data_dir_path() -> Result<PathBuf> {
+    Ok(project_dirs("data")?.data_dir().to_path_buf())
+}
+
+fn project_dirs(file_id: &str) -> Result<directories::ProjectDirs> {
+    directories::ProjectDirs::from("net", "oksenenko", "anki-words-importer")
+        .ok_or(anyhow!("Failed to get config dir path for '{file_id}'"))
+}
+
+// The configured proxy wins over the usual environment variables
+pub fn init_http(settings: &NetworkSettings) -> Result<()> {
+    let proxy = settings.proxy.clone()
+        .or_else(|| ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"].iter()
+            .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty())));
+
+    let proxy = match proxy {
+        Some(proxy) => Some(Url::parse(&proxy).with_context(|| format!("Invalid proxy URL '{proxy}'"))?),
+        None => None,
+    };
+
+    let config = HttpConfig {
+        proxy,
+        no_proxy: no_proxy_from_env(),
+        connect_timeout: Duration::from_secs(settings.connect_timeout_secs),
+        timeout: Duration::from_secs(settings.timeout_secs),
+        request_warn_thresholds: settings.request_warn_thresholds.clone(),
+        connection_verbose: settings.connection_verbose,
+    };
+
+    HTTP_CONFIG.set(config).map_err(|_| anyhow!("HTTP settings are already initialized"))
+}
+
+// Without timeouts a hung connection would block the whole run instead of being retried
+pub fn http_client(default_headers: HeaderMap) -> Result<reqwest::Client> {
+    let config = HTTP_CONFIG.get_or_init(HttpConfig::default);
+
+    let mut builder = reqwest::Client::builder()
+        .default_headers(default_headers)
+        .connect_timeout(config.connect_timeout)
+        .timeout(config.timeout)
+        // reqwest logs the connection traffic at trace level, so it's only worth the overhead when that is shown
+        .connection_verbose(config.connection_verbose || log_enabled!(target: "reqwest::connect::verbose", Level::Trace));
+
+    if let Some(proxy) = config.proxy.clone() {
+        // AnkiConnect runs locally and can't be reached through a remote proxy
+        let no_proxy = config.no_proxy.clone();
+        builder = builder.proxy(Proxy::custom(move |url| {
+            (!is_local(url) && !is_no_proxy(url, &no_proxy)).then(|| proxy.clone())
+        }));
+    }
+
+    Ok(builder.build()?)
+}
+
+// Like curl, NO_PROXY lists hosts and domains, which also match their subdomains, or "*" for every host
+fn no_proxy_from_env() -> Vec<String> {
+    std::env::var("NO_PROXY").or_else(|_| std::env::var("no_proxy")).unwrap_or_default()
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.').to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+fn is_no_proxy(url: &Url, no_proxy: &[String]) -> bool {
+    let Some(host) = url.host_str() else { return false };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    no_proxy.iter().any(|entry| {
+        entry == "*" || host == entry || host.strip_suffix(entry.as_str()).is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+fn is_local(url: &Url) -> bool {
+    matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"))
+}
+
+pub fn describe_request_error(err: &reqwest::Error) -> String {
+    if err.is_timeout() {
+        format!("timeout after {} s", HTTP_CONFIG.get_or_init(HttpConfig::default).timeout.as_secs())
+    } else {
+        format!("error '{err}'")
+    }
+}
+
+// Counted per attempt, since retries use up the quota of the service as well
+pub fn count_request(service: Service) {
+    let count = REQUEST_COUNTS[service as usize].fetch_add(1, Ordering::Relaxed) + 1;
+
+    let threshold = HTTP_CONFIG.get_or_init(HttpConfig::default).request_warn_thresholds.get(&service).copied();
+    if threshold == Some(count) {
+        warn!("Made {count} {service:?} requests, reaching the configured warning threshold");
+    }
+}
+
+pub fn log_request_counts() {
+    let counts = Service::ALL.iter()
+        .map(|service| (service, REQUEST_COUNTS[*service as usize].load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .map(|(service, count)| format!("{service:?}: {count}"))
+        .collect::<Vec<_>>();
+
+    if !counts.is_empty() {
+        info!("HTTP requests made: {}", counts.join(", "));
+    }
+}
+
+// Google escapes quotes and ampersands even in the "text" format, Wiktionary returns HTML
+pub fn decode_html_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest.find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+
+        match entity {
+            Some((c, end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => entity.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_proxy(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn no_proxy_matches_hosts_and_subdomains() {
+        let no_proxy = no_proxy(&["example.com", "10.0.0.1", "::2"]);
+
+        assert!(is_no_proxy(&Url::parse("https://example.com/").unwrap(), &no_proxy));
+        assert!(is_no_proxy(&Url::parse("https://api.example.com/").unwrap(), &no_proxy));
+        assert!(is_no_proxy(&Url::parse("http://10.0.0.1:8765/").unwrap(), &no_proxy));
+        assert!(is_no_proxy(&Url::parse("http://[::2]/").unwrap(), &no_proxy));
+        assert!(!is_no_proxy(&Url::parse("https://notexample.com/").unwrap(), &no_proxy));
+        assert!(!is_no_proxy(&Url::parse("https://readwise.io/").unwrap(), &no_proxy));
+    }
 
-    Ok(result)
+    #[test]
+    fn no_proxy_wildcard_matches_every_host() {
+        assert!(is_no_proxy(&Url::parse("https://readwise.io/").unwrap(), &no_proxy(&["*"])));
+    }
 }
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use anyhow::{anyhow, Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, info};
+use regex::Regex;
+use reqwest::header::{self, HeaderValue};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+
+use crate::dictionary::{Dictionary, DictionaryError};
+use crate::model::{Definition, DefinitionCategory, Definitions, Word};
+use crate::settings::{Service, WiktionarySettings};
+use crate::util;
+
+const DICTIONARY_NAME: &str = "Wiktionary";
+// The definition endpoint only exists on the English Wiktionary, which covers words of every language
+const ENDPOINT: &str = "https://en.wiktionary.org/api/rest_v1/page/definition/";
+
+static HTML_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+static FORM_OF_LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r##"form-of-definition-link.*?title="([^"#]+)""##).unwrap());
+
+pub struct WiktionaryClient {
+    http: reqwest::Client,
+    language: String,
+}
+
+// Usages keyed by the language code of the word
+type DefinitionResponse = HashMap<String, Vec<Usage>>;
+
+#[derive(Deserialize)]
+struct Usage {
+    #[serde(rename = "partOfSpeech")]
+    part_of_speech: String,
+    definitions: Vec<UsageDefinition>,
+}
+
+#[derive(Deserialize)]
+struct UsageDefinition {
+    definition: String,
+    #[serde(default)]
+    examples: Vec<String>,
+}
+
+impl WiktionaryClient {
+    pub fn new(settings: WiktionarySettings) -> Result<WiktionaryClient> {
+        let mut default_headers = header::HeaderMap::new();
+        default_headers.insert("Accept", HeaderValue::from_str("application/json")?);
+        // Wikimedia asks API clients to identify themselves
+        default_headers.insert("User-Agent", HeaderValue::from_str("anki-words-importer")?);
+
+        let http = util::http_client(default_headers)?;
+
+        Ok(WiktionaryClient { http, language: settings.language })
+    }
+
+    // Wiktionary has no lemma lookup, but inflected forms only point to their lemma, e.g. "plural of run"
+    pub async fn word_stem(&self, word: &str) -> Result<String> {
+        let usages = self.usages(word).await?;
+
+        let lemmas: Vec<Option<String>> = usages.iter()
+            .flat_map(|usage| &usage.definitions)
+            .filter(|definition| !definition.definition.trim().is_empty())
+            .map(|definition| FORM_OF_LINK.captures(&definition.definition).map(|captures| captures[1].to_owned()))
+            .collect();
+
+        match lemmas.first() {
+            Some(Some(lemma)) if lemmas.iter().all(Option::is_some) => {
+                debug!("'{word}' is a form of '{lemma}' according to Wiktionary");
+                Ok(util::decode_html_entities(lemma))
+            }
+            _ => Ok(word.to_owned()),
+        }
+    }
+
+    pub async fn definitions(&self, word_stem: &str) -> Result<Word> {
+        let mut definitions = Definitions::new();
+
+        for usage in self.usages(word_stem).await? {
+            let category = DefinitionCategory::from(usage.part_of_speech.trim().to_lowercase().replace(' ', "_"));
+
+            let usage_definitions = usage.definitions.iter()
+                .map(|definition| (Self::plain_text(&definition.definition), &definition.examples))
+                .filter(|(text, _)| !text.is_empty())
+                .map(|(text, examples)| Definition {
+                    definition: Some(text),
+                    examples: examples.iter().map(|example| Self::plain_text(example)).collect(),
+                    grammatical_features: Vec::new(),
+                    registers: Vec::new(),
+                });
+
+            definitions.entry(category).or_default().extend(usage_definitions);
+        }
+
+        definitions.retain(|_, definitions| !definitions.is_empty());
+        if definitions.is_empty() {
+            return Err(DictionaryError::NotFound { dictionary: DICTIONARY_NAME, word: word_stem.to_owned() })?;
+        }
+
+        Ok(Word { definitions: Some(definitions), ..Word::from_text(word_stem) })
+    }
+
+    async fn usages(&self, word: &str) -> Result<Vec<Usage>> {
+        let mut url = Url::parse(ENDPOINT)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid Wiktionary endpoint '{ENDPOINT}'"))?
+            .pop_if_empty()
+            .push(&word.replace(' ', "_"));
+
+        info!("Requesting {url}");
+        util::count_request(Service::Wiktionary);
+
+        let response = self.http.get(url.clone()).send().await
+            .map_err(|err| anyhow!("Request to Wiktionary failed with {}", util::describe_request_error(&err)))?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(DictionaryError::NotFound { dictionary: DICTIONARY_NAME, word: word.to_owned() })?;
+        }
+
+        let status = response.status();
+        let mut usages: DefinitionResponse = response.error_for_status()?.json().await
+            .with_context(|| format!("Unexpected response from Wiktionary for '{url}' (status {status})"))?;
+
+        match usages.remove(&self.language) {
+            Some(usages) => Ok(usages),
+            None => {
+                debug!("Wiktionary has no '{}' entry for '{word}'", self.language);
+                Err(DictionaryError::NotFound { dictionary: DICTIONARY_NAME, word: word.to_owned() })?
+            }
+        }
+    }
+
+    fn plain_text(html: &str) -> String {
+        util::decode_html_entities(&HTML_TAG.replace_all(html, "")).trim().to_owned()
+    }
+}
+
+impl Dictionary for WiktionaryClient {
+    fn name(&self) -> &str {
+        "wiktionary"
+    }
+
+    fn word_stem<'a>(&'a self, word: &'a str) -> BoxFuture<'a, Result<String>> {
+        WiktionaryClient::word_stem(self, word).boxed()
+    }
+
+    fn definitions<'a>(&'a self, word_stem: &'a str) -> BoxFuture<'a, Result<Word>> {
+        WiktionaryClient::definitions(self, word_stem).boxed()
+    }
+}
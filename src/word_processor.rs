@@ -0,0 +1,959 @@
+use std::collections::{HashMap, HashSet};
+use std::io::IsTerminal;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use futures::{stream, try_join, StreamExt, TryStreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use inquire::{MultiSelect, Select, Text};
+use itertools::Itertools;
+use log::{error, info, warn};
+use tokio::sync::OnceCell;
+
+use crate::anki_connect::{AnkiConnectClient, StoreReport};
+use crate::db::{self, FailedWord, Storage};
+use crate::dictionary::{CompositeDictionary, Dictionary, DictionaryError};
+use crate::model::{Book, DefinitionCategory, Provenance, TranslationOrigin, Word};
+use crate::oxford_dict::OxfordDictClient;
+use crate::readwise::ReadwiseClient;
+use crate::render::CardRenderer;
+use crate::settings::{DictionarySource, Settings};
+use crate::translator::{self, Translator};
+use crate::wiktionary::WiktionaryClient;
+
+pub enum BookSelection {
+    Interactive,
+    Id(u64),
+    Title(String),
+    All,
+    OnlyNew,
+}
+
+/// Imports highlighted words: looks them up in the dictionaries, translates them,
+/// caches them in the storage and stores them as Anki cards.
+///
+/// Network clients are only created once they are used, so commands that don't need
+/// an integration work without its credentials.
+pub struct WordProcessor {
+    settings: Settings,
+    offline: bool,
+    readwise: OnceCell<ReadwiseClient>,
+    oxford_dict: OnceCell<Arc<OxfordDictClient>>,
+    dictionary: OnceCell<CompositeDictionary>,
+    translator: OnceCell<Box<dyn Translator>>,
+    anki: AnkiConnectClient,
+    renderer: CardRenderer,
+    storage: Box<dyn Storage>,
+    concurrency: usize,
+    retry_incomplete: bool,
+    include_categories: Vec<DefinitionCategory>,
+    limit: Option<usize>,
+    log_interval: usize,
+    skip_known: bool,
+    group_by_lemma: bool,
+    interactive: bool,
+    attach_audio: bool,
+    assume_yes: bool,
+}
+
+impl WordProcessor {
+    /// Expects `db::init_data_dir` and `util::init_http` to have run, see the crate docs
+    pub fn new(settings: &Settings) -> Result<WordProcessor> {
+        if settings.dictionaries.is_empty() {
+            bail!("No dictionaries configured in settings");
+        }
+
+        let renderer = CardRenderer::new(settings.rendering.clone())?;
+
+        Ok(WordProcessor {
+            settings: settings.clone(),
+            offline: settings.processing.offline,
+            readwise: OnceCell::new(),
+            oxford_dict: OnceCell::new(),
+            dictionary: OnceCell::new(),
+            translator: OnceCell::new(),
+            anki: AnkiConnectClient::new(settings.anki.clone(), renderer.clone())?,
+            renderer,
+            storage: db::open(&settings.storage)?,
+            concurrency: settings.processing.concurrency.max(1),
+            retry_incomplete: settings.processing.retry_incomplete,
+            include_categories: settings.processing.include_categories.clone(),
+            limit: settings.processing.limit,
+            log_interval: settings.processing.log_interval.max(1),
+            skip_known: settings.processing.skip_known,
+            group_by_lemma: settings.processing.group_by_lemma,
+            interactive: settings.processing.interactive,
+            attach_audio: settings.rendering.show_audio,
+            assume_yes: settings.processing.assume_yes,
+        })
+    }
+
+    /// The Readwise client, e.g. to list the books with `get_books`
+    pub async fn readwise(&self) -> Result<&ReadwiseClient> {
+        self.ensure_online("Readwise")?;
+
+        self.readwise.get_or_try_init(|| ReadwiseClient::new(self.settings.readwise.clone())).await
+    }
+
+    async fn oxford_dict(&self) -> Result<&Arc<OxfordDictClient>> {
+        self.ensure_online("Oxford dict")?;
+
+        self.oxford_dict
+            .get_or_try_init(|| async { Ok(Arc::new(OxfordDictClient::new(self.settings.oxford.clone()).await?)) })
+            .await
+    }
+
+    async fn dictionary(&self) -> Result<&CompositeDictionary> {
+        self.dictionary
+            .get_or_try_init(|| async {
+                let mut dictionaries = Vec::new();
+                let mut errors = Vec::new();
+                for source in &self.settings.dictionaries {
+                    let dictionary = match source {
+                        DictionarySource::Oxford => {
+                            self.oxford_dict().await.map(|oxford| oxford.clone() as Arc<dyn Dictionary>)
+                        }
+                        DictionarySource::Wiktionary => self.ensure_online("Wiktionary")
+                            .and_then(|_| WiktionaryClient::new(self.settings.wiktionary.clone()))
+                            .map(|wiktionary| Arc::new(wiktionary) as Arc<dyn Dictionary>),
+                    };
+
+                    // The other sources still work without the credentials of one of them
+                    match dictionary {
+                        Ok(dictionary) => dictionaries.push(dictionary),
+                        Err(err) => {
+                            warn!("Skipping the {source:?} dictionary: {err:#}");
+                            errors.push(format!("{source:?}: {err:#}"));
+                        }
+                    }
+                }
+
+                if dictionaries.is_empty() {
+                    bail!("No dictionary is available:\n{}", errors.join("\n"));
+                }
+
+                Ok(CompositeDictionary::new(dictionaries))
+            })
+            .await
+    }
+
+    async fn translator(&self) -> Result<&dyn Translator> {
+        self.ensure_online("Translator")?;
+
+        let translator = self.translator
+            .get_or_try_init(|| translator::create(&self.settings.translation, &self.settings.oxford.source_language))
+            .await?;
+
+        Ok(translator.as_ref())
+    }
+
+    fn ensure_online(&self, client: &str) -> Result<()> {
+        if self.offline {
+            bail!("{client} isn't available in offline mode");
+        }
+
+        Ok(())
+    }
+
+    /// Processes the selected books and stores the words of each in its Anki deck
+    pub async fn process(&self, force: bool, selection: BookSelection, dry_run: bool) -> Result<()> {
+        if !dry_run {
+            self.anki.validate_note_type().await?;
+        }
+
+        let books = self.select_books(selection).await?;
+        let mut report = StoreReport::default();
+        let mut failed_books = Vec::new();
+        for book in &books {
+            info!("Processing book '{book}'");
+            match self.process_book(book, force, dry_run).await {
+                Ok(book_report) => report.merge(book_report),
+                // Every other book would fail the same way
+                Err(err) if DictionaryError::is_credentials_rejected(&err) => return Err(err),
+                Err(err) => {
+                    error!("Failed to process book '{book}': {err:#}");
+                    failed_books.push(book.to_string());
+                }
+            }
+        }
+
+        if !dry_run {
+            info!("Added {} cards, skipped {} duplicates, {} failed", report.added, report.duplicates, report.failed);
+        }
+
+        if !failed_books.is_empty() {
+            bail!("Failed to process {} of {} books: {}", failed_books.len(), books.len(), failed_books.join(", "));
+        }
+
+        Ok(())
+    }
+
+    async fn select_books(&self, selection: BookSelection) -> Result<Vec<Book>> {
+        let mut books = if self.offline {
+            self.cached_books().await?
+        } else {
+            self.readwise_books().await?
+        };
+        books.sort();
+
+        let books = match selection {
+            BookSelection::Interactive => vec![Self::select_book(books)?],
+            BookSelection::Id(id) => vec![Self::find_book(books, |book| book.id == id, &id.to_string())?],
+            BookSelection::Title(title) => {
+                vec![Self::find_book(books, |book| book.title.eq_ignore_ascii_case(&title), &title)?]
+            }
+            BookSelection::All => books,
+            BookSelection::OnlyNew => self.filter_new_books(books).await,
+        };
+
+        Ok(books)
+    }
+
+    /// Looks the given cached words of the selected books up again, updating only their cached
+    /// entries and cards and leaving the other words untouched
+    pub async fn reprocess_words(&self, selection: BookSelection, texts: &[String], dry_run: bool) -> Result<()> {
+        if !dry_run {
+            self.anki.validate_note_type().await?;
+        }
+
+        for book in self.select_books(selection).await? {
+            self.reprocess_book_words(&book, texts, dry_run).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn reprocess_book_words(&self, book: &Book, texts: &[String], dry_run: bool) -> Result<()> {
+        let mut words = self.storage.get_words(book).await?;
+
+        let matches = |word: &Word, text: &str| {
+            word.text.eq_ignore_ascii_case(text) || word.original_text.eq_ignore_ascii_case(text)
+        };
+
+        let missing = texts.iter().filter(|text| !words.iter().any(|word| matches(word, text))).join(", ");
+        if !missing.is_empty() {
+            bail!("Words {missing} aren't cached for book '{book}'");
+        }
+
+        let mut positions = Vec::new();
+        let mut reprocessed_words = Vec::new();
+        let mut failed = Vec::new();
+        for position in words.iter().positions(|word| texts.iter().any(|text| matches(word, text))) {
+            let cached = &words[position];
+            let mut word = Word::from_text(&cached.original_text);
+            word.inflections = cached.inflections.clone();
+            word.context = cached.context.clone();
+
+            match self.process_word(&mut word).await {
+                Ok(()) => {
+                    positions.push(position);
+                    reprocessed_words.push(word);
+                }
+                Err(err) => {
+                    warn!("Failed to reprocess '{cached}', keeping the cached word: {err}");
+                    failed.push(cached.to_string());
+                }
+            }
+        }
+
+        if dry_run {
+            info!("Dry run, not saving words or updating them in Anki");
+            self.print_cards(&reprocessed_words)?;
+            return Self::check_reprocessed(book, &failed);
+        }
+
+        if self.attach_audio {
+            self.attach_audio(&mut reprocessed_words).await?;
+        }
+
+        // The cache only changes for the words whose card was updated, so both stay in sync
+        let mut updated = 0;
+        for (position, word) in positions.into_iter().zip(reprocessed_words) {
+            match self.anki.update_word(book, &words[position], &word).await {
+                Ok(()) => {
+                    info!("Reprocessed '{word}' in '{book}'");
+                    words[position] = word;
+                    updated += 1;
+                }
+                Err(err) => {
+                    warn!("Failed to update the card of '{}': {err}", words[position]);
+                    failed.push(words[position].to_string());
+                }
+            }
+        }
+
+        if updated > 0 {
+            self.storage.save_words(book, &words).await?;
+        }
+
+        info!("Updated {updated} of {} words in '{book}'", updated + failed.len());
+
+        Self::check_reprocessed(book, &failed)
+    }
+
+    fn check_reprocessed(book: &Book, failed: &[String]) -> Result<()> {
+        if !failed.is_empty() {
+            bail!("Failed to reprocess {} in '{book}'", failed.join(", "));
+        }
+
+        Ok(())
+    }
+
+    /// Adds a processed word to a deck and/or to the cached words of a book
+    pub async fn add_word(&self, mut word: Word, deck_name: Option<&str>, book_title: Option<&str>) -> Result<()> {
+        let book = match book_title {
+            Some(title) => Some(self.find_book_by_title(title).await?),
+            None => None,
+        };
+
+        if let Some(deck_name) = deck_name {
+            self.anki.validate_note_type().await?;
+
+            if self.attach_audio {
+                self.attach_audio(std::slice::from_mut(&mut word)).await?;
+            }
+
+            let report = self.anki.add_word(book.as_ref(), deck_name, &word).await?;
+            if report.added > 0 {
+                info!("Added '{word}' to deck '{deck_name}'");
+            } else if report.duplicates > 0 {
+                info!("'{word}' is already in deck '{deck_name}'");
+            }
+        }
+
+        if let Some(book) = &book {
+            info!("Saving '{word}' to the cached words of '{book}'");
+
+            let mut words = self.storage.get_words(book).await?;
+            match words.iter().position(|cached| cached.original_text == word.original_text) {
+                Some(position) => words[position] = word,
+                None => words.push(word),
+            }
+
+            self.storage.save_words(book, &words).await?;
+        }
+
+        Ok(())
+    }
+
+    // Books that were imported before are found in the storage without asking Readwise
+    async fn find_book_by_title(&self, title: &str) -> Result<Book> {
+        if let Some(book) = self.storage.find_book(title).await? {
+            return Ok(book);
+        }
+
+        let books = self.readwise_books().await?;
+        Self::find_book(books, |book| book.title.eq_ignore_ascii_case(title), title)
+    }
+
+    // Legacy files only have the title slug, which would name the wrong deck
+    async fn cached_books(&self) -> Result<Vec<Book>> {
+        let (legacy, cached): (Vec<_>, Vec<_>) = self.storage.list_books().await?.into_iter()
+            .partition(|cached| cached.legacy);
+
+        if !legacy.is_empty() {
+            warn!("Skipping cached books saved without their title ({}), run process-all online once to migrate them",
+                legacy.iter().map(|cached| &cached.book.title).join(", "));
+        }
+
+        Ok(cached.into_iter().map(|cached| cached.book).collect())
+    }
+
+    // Only the Readwise books tell which book a legacy cache file belongs to
+    async fn readwise_books(&self) -> Result<Vec<Book>> {
+        let books = self.readwise().await?.get_books().await?;
+        self.storage.migrate_legacy_files(&books).await?;
+
+        Ok(books)
+    }
+
+    /// Processes the highlighted words of a book, saves them and stores them in Anki
+    pub async fn process_book(&self, book: &Book, force: bool, dry_run: bool) -> Result<StoreReport> {
+        // Asking before the words are processed, `force` recreates the deck when storing them
+        if force && !dry_run {
+            self.anki.confirm_delete_deck(&self.settings.anki.deck_name(book), self.assume_yes).await?;
+        }
+
+        if self.offline {
+            return self.store_cached_book(book, force, dry_run).await;
+        }
+
+        let mut all_words = self.readwise().await?.get_words(book).await?;
+
+        let known_words = if self.skip_known { self.known_words(book).await? } else { HashSet::new() };
+        if !known_words.is_empty() {
+            Self::skip_known_words(&mut all_words, &known_words, |word| &word.original_text);
+        }
+
+        let result = self.process_words_v2(book, all_words, force).await;
+        self.flush_caches().await;
+        let (mut processed_words, failed_words) = result?;
+
+        // Different highlights can share a stem that is only known after processing
+        if !known_words.is_empty() {
+            Self::skip_known_words(&mut processed_words, &known_words, |word| &word.text);
+        }
+
+        if dry_run {
+            info!("Dry run, not saving words or storing them in Anki");
+            self.print_cards(&processed_words)?;
+            return Ok(StoreReport::default());
+        }
+
+        if !self.interactive {
+            if !failed_words.is_empty() {
+                warn!("{} words of '{book}' failed, they are recorded in '{}'", failed_words.len(), db::failed_words_filename());
+            }
+            db::save_failed_words(book, failed_words).await?;
+        }
+
+        if self.readwise().await?.is_date_filtered() {
+            self.keep_cached_words(book, &mut processed_words).await?;
+        }
+
+        if self.attach_audio {
+            self.attach_audio(&mut processed_words).await?;
+        }
+
+        self.storage.save_words(book, &processed_words).await?;
+
+        self.store_book(book, &processed_words, force).await
+    }
+
+    async fn store_cached_book(&self, book: &Book, force: bool, dry_run: bool) -> Result<StoreReport> {
+        let mut words = self.storage.get_words(book).await?;
+        if words.is_empty() {
+            bail!("No cached words found for book '{book}'");
+        }
+
+        if dry_run {
+            info!("Dry run, not storing words in Anki");
+            self.print_cards(&words)?;
+            return Ok(StoreReport::default());
+        }
+
+        self.drop_missing_audio(&mut words).await?;
+
+        self.store_book(book, &words, force).await
+    }
+
+    // Cached audio files are only in the media folder of the Anki profile they were stored in,
+    // e.g. restoring into a fresh profile would leave broken sound references on the cards
+    async fn drop_missing_audio(&self, words: &mut [Word]) -> Result<()> {
+        if words.iter().all(|word| word.audio_file.is_none()) {
+            return Ok(());
+        }
+
+        let media_files: HashSet<String> = self.anki.audio_file_names().await?.into_iter().collect();
+
+        let mut missing = 0;
+        for word in words.iter_mut() {
+            if word.audio_file.as_ref().is_some_and(|audio_file| !media_files.contains(audio_file)) {
+                word.audio_file = None;
+                missing += 1;
+            }
+        }
+
+        if missing > 0 {
+            warn!("{missing} cached audio files aren't in Anki's media folder, storing their cards without audio");
+        }
+
+        Ok(())
+    }
+
+    async fn store_book(&self, book: &Book, words: &Vec<Word>, force: bool) -> Result<StoreReport> {
+        let report = self.anki.store_book(book, words, force).await?;
+
+        if !report.found_in_other_decks.is_empty() {
+            let found_words = report.found_in_other_decks.iter()
+                .map(|(word, decks)| format!("{word} ({})", decks.join(", ")))
+                .join("; ");
+            info!("{} words already exist in other decks: {found_words}",
+                report.found_in_other_decks.len());
+        }
+
+        Ok(report)
+    }
+
+    async fn known_words(&self, book: &Book) -> Result<HashSet<String>> {
+        let mut known_words = HashSet::new();
+
+        for cached in self.storage.list_books().await? {
+            if cached.book.id == book.id || cached.book.title.eq_ignore_ascii_case(&book.title) {
+                continue;
+            }
+
+            for word in self.storage.get_words(&cached.book).await? {
+                known_words.insert(word.original_text);
+                known_words.insert(word.text);
+            }
+        }
+
+        // Words this book already has stay in its cache, even if other books imported them later
+        for word in self.storage.get_words(book).await? {
+            known_words.remove(&word.original_text);
+            known_words.remove(&word.text);
+        }
+
+        Ok(known_words)
+    }
+
+    fn skip_known_words(words: &mut Vec<Word>, known_words: &HashSet<String>, key: impl Fn(&Word) -> &String) {
+        let count = words.len();
+        words.retain(|word| !known_words.contains(key(word)));
+
+        if words.len() < count {
+            info!("Skipping {} words already imported from other books", count - words.len());
+        }
+    }
+
+    // Saving only the highlights from the date range would drop the words cached by earlier runs
+    async fn keep_cached_words(&self, book: &Book, words: &mut Vec<Word>) -> Result<()> {
+        let cached_words = self.storage.get_words(book).await?;
+        let new_texts: HashSet<String> = words.iter().map(|word| word.original_text.to_owned()).collect();
+
+        words.extend(cached_words.into_iter().filter(|word| !new_texts.contains(&word.original_text)));
+
+        Ok(())
+    }
+
+    /// Renders the back of the word's card as HTML
+    pub fn render_card(&self, word: &Word) -> Result<String> {
+        self.renderer.back_html(word)
+    }
+
+    pub fn print_cards(&self, words: &[Word]) -> Result<()> {
+        for word in words {
+            println!("{word}\n{}\n", self.render_card(word)?);
+        }
+
+        Ok(())
+    }
+
+    async fn attach_audio(&self, words: &mut [Word]) -> Result<()> {
+        // Only Oxford provides audio, so don't require its credentials when there is nothing to download
+        if !words.iter().any(|word| word.audio_file.is_none() && word.audio_url.is_some()) {
+            return Ok(());
+        }
+
+        let oxford_dict = self.oxford_dict().await?;
+
+        stream::iter(words.iter_mut().filter(|word| word.audio_file.is_none()))
+            .for_each_concurrent(self.concurrency, |word| async move {
+                if let Some(url) = &word.audio_url {
+                    let audio_file = match oxford_dict.download_audio(url).await {
+                        Ok(data) => self.anki.store_audio(&word.text, url, &data).await,
+                        Err(err) => Err(err),
+                    };
+
+                    match audio_file {
+                        Ok(audio_file) => word.audio_file = Some(audio_file),
+                        Err(err) => warn!("Failed to attach audio for '{word}': {err}"),
+                    }
+                }
+            })
+            .await;
+
+        Ok(())
+    }
+
+    async fn filter_new_books(&self, books: Vec<Book>) -> Vec<Book> {
+        let mut new_books = Vec::new();
+
+        for book in books {
+            if self.storage.has_words(&book).await {
+                info!("Skipping already imported book '{book}'");
+            } else {
+                new_books.push(book);
+            }
+        }
+
+        new_books
+    }
+
+    // Without `interactive` the failed words are returned to be recorded instead of being redacted
+    async fn process_words_v2(&self, book: &Book, all_words: Vec<Word>, force: bool) -> Result<(Vec<Word>, Vec<FailedWord>)> {
+        let (mut unprocessed_words, mut processed_words) = if !force {
+            self.partition_by_processed(book, all_words).await?
+        } else {
+            (all_words, Vec::new())
+        };
+
+        if let Some(limit) = self.limit {
+            if unprocessed_words.len() > limit {
+                info!("Limiting processing to the first {limit} of {} words", unprocessed_words.len());
+                unprocessed_words.truncate(limit);
+            }
+        }
+
+        let total = unprocessed_words.len();
+        let previously_processed = processed_words.len();
+        let progress = Self::progress_bar(total);
+
+        let mut count = 0;
+        let mut grouped = 0;
+        let mut recorded_failures = Vec::new();
+        while !unprocessed_words.is_empty() {
+            let mut failed_words: Vec<Word> = Vec::new();
+
+            let mut word_stems: Vec<String> = stream::iter(&unprocessed_words)
+                .map(|word| self.word_stem(word))
+                .buffered(self.concurrency)
+                .try_collect().await?;
+
+            if self.group_by_lemma {
+                let words_count = unprocessed_words.len();
+                (unprocessed_words, word_stems) = Self::group_by_lemma(unprocessed_words, word_stems, &mut processed_words);
+
+                let grouped_now = words_count - unprocessed_words.len();
+                grouped += grouped_now;
+                count += grouped_now;
+                progress.inc(grouped_now as u64);
+            }
+
+            let translations = self.pre_translate(&unprocessed_words, &word_stems).await;
+
+            let mut results = stream::iter(unprocessed_words.into_iter().zip(word_stems).zip(translations))
+                .map(|((mut word, word_stem), translation)| async move {
+                    let result = self.define_word(&mut word, &word_stem, translation).await;
+                    (word, result)
+                })
+                .buffered(self.concurrency);
+
+            while let Some((word, result)) = results.next().await {
+                match result {
+                    Ok(()) => processed_words.push(word),
+                    Err(err) if DictionaryError::is_credentials_rejected(&err) => return Err(err),
+                    Err(err) => {
+                        progress.suspend(|| error!("Failed to process word '{word}': {err}"));
+                        if self.interactive {
+                            failed_words.push(word);
+                        } else {
+                            recorded_failures.push(FailedWord { word: word.original_text, error: format!("{err:#}") });
+                        }
+                    }
+                };
+
+                count += 1;
+                progress.inc(1);
+                if progress.is_hidden() && count % self.log_interval == 0 {
+                    info!("Processed {count} words");
+                }
+            }
+
+            if !failed_words.is_empty() {
+                unprocessed_words = progress.suspend(|| Self::redact_words(failed_words))?;
+                progress.inc_length(unprocessed_words.len() as u64);
+            } else {
+                break;
+            }
+        }
+
+        progress.finish_and_clear();
+
+        if total > 0 {
+            let processed = processed_words.len() - previously_processed;
+            info!("Processed {processed}/{total} words ({} failed)", total - processed - grouped);
+
+            if grouped > 0 {
+                info!("{grouped} words were recorded as inflections of other words");
+            }
+        }
+
+        Ok((processed_words, recorded_failures))
+    }
+
+    // Keeps the first word of every lemma, the others only end up in its inflections
+    fn group_by_lemma(words: Vec<Word>, word_stems: Vec<String>, processed_words: &mut [Word]) -> (Vec<Word>, Vec<String>) {
+        let processed_positions: HashMap<String, usize> = processed_words.iter()
+            .enumerate()
+            .map(|(position, word)| (word.text.to_owned(), position))
+            .collect();
+        let mut grouped: Vec<(Word, String)> = Vec::new();
+        let mut grouped_positions: HashMap<String, usize> = HashMap::new();
+
+        for (word, word_stem) in words.into_iter().zip(word_stems) {
+            if let Some(&position) = processed_positions.get(&word_stem) {
+                processed_words[position].add_inflection(word.original_text);
+            } else if let Some(&position) = grouped_positions.get(&word_stem) {
+                grouped[position].0.add_inflection(word.original_text);
+            } else {
+                grouped_positions.insert(word_stem.to_owned(), grouped.len());
+                grouped.push((word, word_stem));
+            }
+        }
+
+        grouped.into_iter().unzip()
+    }
+
+    fn progress_bar(total: usize) -> ProgressBar {
+        if !std::io::stderr().is_terminal() {
+            return ProgressBar::hidden();
+        }
+
+        ProgressBar::new(total as u64)
+            .with_style(ProgressStyle::with_template("{bar:40} {pos}/{len} words, ETA {eta}").unwrap())
+    }
+
+    /// Fills in the word's lemma, translation and definitions
+    pub async fn process_word(&self, word: &mut Word) -> Result<()> {
+        let result = async {
+            let word_stem = self.word_stem(word).await?;
+            self.define_word(word, &word_stem, None).await
+        }.await;
+        self.flush_caches().await;
+
+        result
+    }
+
+    // The lemma and translation caches are saved in batches, the rest once the words are processed
+    async fn flush_caches(&self) {
+        if let Some(dictionary) = self.dictionary.get() {
+            dictionary.flush().await;
+        }
+
+        if let Some(translator) = self.translator.get() {
+            translator.flush().await;
+        }
+    }
+
+    async fn word_stem(&self, word: &Word) -> Result<String> {
+        match self.dictionary().await?.word_stem(&word.text).await {
+            Ok(word_stem) => Ok(word_stem),
+            Err(err) if DictionaryError::is_credentials_rejected(&err) => Err(err),
+            Err(_) => Ok(word.text.to_owned()),
+        }
+    }
+
+    async fn pre_translate(&self, words: &[Word], word_stems: &[String]) -> Vec<Option<(String, TranslationOrigin)>> {
+        let queries = words.iter().zip(word_stems)
+            .filter(|(word, _)| word.translation.is_none())
+            .map(|(_, word_stem)| word_stem.to_owned())
+            .unique()
+            .collect_vec();
+
+        let batch = async {
+            let translator = self.translator().await?;
+
+            let mut origins = Vec::with_capacity(queries.len());
+            for query in &queries {
+                origins.push(Self::translation_origin(translator, query).await);
+            }
+
+            Ok::<_, anyhow::Error>((translator.translate_batch(&queries).await?, origins))
+        };
+        let translations: HashMap<String, (String, TranslationOrigin)> = match batch.await {
+            Ok((translations, origins)) => queries.into_iter().zip(translations).zip(origins)
+                .filter_map(|((query, translation), origin)| Some((query, (translation?, origin))))
+                .collect(),
+            Err(err) => {
+                warn!("Batch translation failed, translating words one by one: {err}");
+                HashMap::new()
+            }
+        };
+
+        word_stems.iter()
+            .map(|word_stem| translations.get(word_stem).cloned())
+            .collect()
+    }
+
+    // Asked before translating, since the translation is cached afterwards either way
+    async fn translation_origin(translator: &dyn Translator, query: &str) -> TranslationOrigin {
+        if translator.is_cached(query).await {
+            TranslationOrigin::Cached
+        } else {
+            TranslationOrigin::Fresh
+        }
+    }
+
+    async fn define_word(&self, word: &mut Word, word_stem: &str, translation: Option<(String, TranslationOrigin)>) -> Result<()> {
+        let translation = async {
+            match (&word.translation, translation) {
+                (Some(translation), _) => Ok((Some(translation.to_owned()), TranslationOrigin::Preset)),
+                (None, Some((translation, origin))) => Ok((Some(translation), origin)),
+                (None, None) => {
+                    let translator = self.translator().await?;
+                    let origin = Self::translation_origin(translator, word_stem).await;
+
+                    translator.translate(word_stem).await.map(|translation| (translation, origin))
+                }
+            }
+        };
+
+        // Only rejected credentials abort the word here, a missing entry is decided on
+        // once it's known whether there is a translation to fall back to
+        let definitions = async {
+            match self.dictionary().await?.definitions(word_stem).await {
+                Err(err) if DictionaryError::is_credentials_rejected(&err) => Err(err),
+                result => Ok(result),
+            }
+        };
+
+        let ((translation, translation_origin), defined_word) = try_join!(translation, definitions)?;
+        // Other failures, e.g. timeouts, fail the word so it's retried instead of cached without definitions
+        let defined_word = match defined_word {
+            Ok(defined_word) => defined_word,
+            Err(err) if translation.is_some() && (word.is_phrase() || DictionaryError::is_not_found(&err)) => {
+                warn!("No definitions for '{word}', keeping only the translation: {err:#}");
+                Word::from_text(word_stem)
+            }
+            Err(err) => return Err(err),
+        };
+
+        *word = Word {
+            original_text: std::mem::take(&mut word.original_text),
+            inflections: std::mem::take(&mut word.inflections),
+            context: word.context.take(),
+            translation,
+            ..defined_word
+        };
+        word.provenance.get_or_insert_with(Provenance::default).translation = Some(translation_origin);
+
+        self.filter_categories(word);
+
+        Ok(())
+    }
+
+    fn filter_categories(&self, word: &mut Word) {
+        if self.include_categories.is_empty() {
+            return;
+        }
+
+        if let Some(definitions) = &mut word.definitions {
+            let had_definitions = !definitions.is_empty();
+            definitions.retain(|category, _| self.include_categories.contains(category));
+
+            if had_definitions && definitions.is_empty() {
+                info!("All definitions of '{word}' were filtered out by include_categories, keeping only the translation");
+            }
+        }
+    }
+
+    fn select_book(books: Vec<Book>) -> Result<Book> {
+        Ok(Select::new("Select the book to import:", books)
+            .with_page_size(20)
+            .prompt()?)
+    }
+
+    fn find_book(mut books: Vec<Book>, predicate: impl Fn(&Book) -> bool, selector: &str) -> Result<Book> {
+        match books.iter().position(predicate) {
+            Some(position) => Ok(books.swap_remove(position)),
+            None => {
+                let available = books.iter()
+                    .map(|book| format!("{} ({book})", book.id))
+                    .join("\n");
+                bail!("No book matches '{selector}', available books:\n{available}")
+            }
+        }
+    }
+
+    fn redact_words(words: Vec<Word>) -> Result<Vec<Word>> {
+        let selected = MultiSelect::new("Select words to redact: ", words)
+            .prompt()?;
+
+        let mut new_words = Vec::new();
+        for word in selected {
+            let redacted_text = Text::new("Redact: ")
+                .with_initial_value(&word.text)
+                .prompt()?;
+
+            new_words.push(Word {
+                original_text: word.original_text,
+                context: word.context,
+                ..Word::from_text(&redacted_text)
+            });
+        }
+
+        Ok(new_words)
+    }
+
+    async fn partition_by_processed(&self, book: &Book, words: Vec<Word>) -> Result<(Vec<Word>, Vec<Word>)> {
+        let cached_words = self.storage.get_words(book).await?;
+
+        Ok(Self::partition_cached(words, cached_words, self.group_by_lemma, self.retry_incomplete))
+    }
+
+    fn partition_cached(words: Vec<Word>, cached_words: Vec<Word>, group_by_lemma: bool, retry_incomplete: bool) -> (Vec<Word>, Vec<Word>) {
+        let inflections: HashSet<String> = cached_words.iter()
+            .flat_map(|word| word.inflections.iter().cloned())
+            .collect();
+        let mut cached_words = cached_words.into_iter()
+            .map(|word| (word.original_text.clone(), word))
+            .collect::<HashMap<String, Word>>();
+
+        let (mut processed, mut unprocessed) = (Vec::new(), Vec::new());
+
+        for word in words.into_iter() {
+            if group_by_lemma && inflections.contains(&word.original_text) {
+                continue;
+            }
+
+            match cached_words.remove(&word.original_text) {
+                Some(mut cached_word) if cached_word.is_complete() || !retry_incomplete => {
+                    // Words cached before the context was kept get it from the highlight
+                    if cached_word.context.is_none() {
+                        cached_word.context = word.context;
+                    }
+                    processed.push(cached_word);
+                }
+                Some(_) => {
+                    info!("Cached word '{word}' is incomplete, processing it again");
+                    unprocessed.push(word);
+                }
+                None => unprocessed.push(word),
+            }
+        }
+
+        (unprocessed, processed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::model::Definition;
+
+    fn cached(text: &str, with_definitions: bool) -> Word {
+        let definition = Definition {
+            definition: Some(format!("definition of {text}")),
+            examples: Vec::new(),
+            grammatical_features: Vec::new(),
+            registers: Vec::new(),
+        };
+
+        Word {
+            translation: Some(format!("translation of {text}")),
+            definitions: with_definitions.then(|| HashMap::from([(DefinitionCategory::Verb, vec![definition])])),
+            ..Word::from_text(text)
+        }
+    }
+
+    fn texts(words: &[Word]) -> Vec<&str> {
+        words.iter().map(|word| word.text.as_str()).collect()
+    }
+
+    #[test]
+    fn reprocesses_incomplete_cached_words() {
+        let highlights = vec![Word::from_text("run"), Word::from_text("give"), Word::from_text("take")];
+        let cached_words = vec![cached("run", false), cached("give", true)];
+
+        let (unprocessed, processed) = WordProcessor::partition_cached(highlights, cached_words, false, true);
+
+        assert_eq!(texts(&unprocessed), vec!["run", "take"]);
+        assert_eq!(texts(&processed), vec!["give"]);
+        assert!(processed[0].definitions.is_some());
+    }
+
+    #[test]
+    fn keeps_incomplete_cached_words_without_retry() {
+        let highlights = vec![Word::from_text("run"), Word::from_text("give")];
+        let cached_words = vec![cached("run", false), cached("give", true)];
+
+        let (unprocessed, processed) = WordProcessor::partition_cached(highlights, cached_words, false, false);
+
+        assert!(unprocessed.is_empty());
+        assert_eq!(texts(&processed), vec!["run", "give"]);
+    }
+}